@@ -0,0 +1,122 @@
+//! Admin API to inspect and manage live order sessions, for users with the `admin` role.
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use crate::handlers::event_actor::AdminCommand;
+use crate::handlers::incoming_order_processor::HANDLERS;
+use crate::handlers::reassignment::{reassign, ReassignError};
+use crate::handlers::states::StateKind;
+use crate::jwt_auth;
+use crate::models::reassignment::CourierReassignment;
+
+const DEFAULT_DISCONNECT_REASON: &str = "Disconnected by an operator";
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:order_id", get(get_session).delete(terminate_session))
+        .route("/sessions/:order_id/transition", post(force_transition))
+        .route("/sessions/:order_id/reassign", post(reassign_courier))
+        .route("/sessions/:order_id/:participant/disconnect", post(disconnect_participant))
+        .route_layer(axum::middleware::from_fn(jwt_auth::require_admin))
+}
+
+async fn list_sessions() -> impl IntoResponse {
+    Json(HANDLERS.iter().map(|session| session.info()).collect::<Vec<_>>())
+}
+
+async fn get_session(order_id: Path<String>) -> impl IntoResponse {
+    match HANDLERS.get(order_id.as_str()) {
+        Some(session) => Json(session.info()).into_response(),
+        None => (StatusCode::NOT_FOUND, "Order not found").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TransitionRequest {
+    state: String,
+}
+
+/// Moves the order to any state, e.g. to unstick an order whose participant can't continue.
+async fn force_transition(order_id: Path<String>, Json(request): Json<TransitionRequest>) -> impl IntoResponse {
+    let state = match request.state.parse::<StateKind>() {
+        Ok(state) => state,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    // The session must not be borrowed from HANDLERS while waiting for it
+    let Some(admin) = HANDLERS.get(order_id.as_str()).map(|session| session.admin()) else {
+        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    };
+    let (reply, result) = oneshot::channel();
+    if admin.send(AdminCommand::Transition(state, reply)).await.is_err() {
+        return (StatusCode::GONE, "Order session has ended").into_response();
+    }
+    match result.await {
+        Ok(Ok(())) => get_session(order_id).await.into_response(),
+        Ok(Err(e)) => (StatusCode::CONFLICT, e).into_response(),
+        Err(_) => (StatusCode::GONE, "Order session has ended").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReassignRequest {
+    courier_id: String,
+    reason: Option<String>,
+}
+
+/// Hands the order to another courier, as a reassignment on `order_reassignments` does.
+async fn reassign_courier(order_id: Path<String>, Json(request): Json<ReassignRequest>) -> impl IntoResponse {
+    let reassignment = CourierReassignment {
+        order_id: order_id.to_string(),
+        courier_id: request.courier_id,
+        reason: request.reason,
+    };
+    match reassign(reassignment).await {
+        Ok(_) => get_session(order_id).await.into_response(),
+        Err(e @ ReassignError::OrderNotFound) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e @ ReassignError::SessionEnded) => (StatusCode::GONE, e.to_string()).into_response(),
+        Err(e @ ReassignError::Refused(_)) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DisconnectRequest {
+    reason: Option<String>,
+}
+
+/// Closes a participant's socket. The participant can reconnect.
+async fn disconnect_participant(
+    Path((order_id, participant)): Path<(String, String)>,
+    request: Option<Json<DisconnectRequest>>,
+) -> impl IntoResponse {
+    let reason = request.and_then(|Json(request)| request.reason)
+        .unwrap_or_else(|| DEFAULT_DISCONNECT_REASON.to_string());
+    let Some(mut session) = HANDLERS.get_mut(order_id.as_str()) else {
+        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    };
+    let connected = match participant.as_str() {
+        "customer" => session.disconnect_customer(reason),
+        "courier" => session.disconnect_courier(reason),
+        _ => return (StatusCode::NOT_FOUND, "Unknown participant").into_response(),
+    };
+    match connected {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => (StatusCode::NOT_FOUND, "Participant not connected").into_response(),
+    }
+}
+
+/// Ends the session. The order is published to `processed_orders` as terminated and its slot is freed.
+async fn terminate_session(order_id: Path<String>) -> impl IntoResponse {
+    let Some(admin) = HANDLERS.get(order_id.as_str()).map(|session| session.admin()) else {
+        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    };
+    match admin.send(AdminCommand::Terminate).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => (StatusCode::GONE, "Order session has ended").into_response(),
+    }
+}
@@ -1,7 +1,28 @@
 mod handler;
 mod events;
 mod processor;
-mod event_actor;
+pub(crate) mod event_actor;
 pub mod websocket_actor;
 pub mod incoming_order_processor;
-pub(crate) mod location_logger;
\ No newline at end of file
+pub(crate) mod location_logger;
+pub(crate) mod location_spool;
+pub(crate) mod location_log_encoder;
+pub(crate) mod protocol;
+pub(crate) mod protocol_schema;
+pub(crate) mod reply_cache;
+pub(crate) mod state_machine;
+pub(crate) mod states;
+pub(crate) mod workflow;
+pub(crate) mod throttle;
+pub(crate) mod geofence;
+pub(crate) mod route_deviation;
+pub(crate) mod alert_publisher;
+pub(crate) mod stall_detector;
+pub(crate) mod order_store;
+pub(crate) mod track_store;
+pub(crate) mod proof_of_delivery;
+pub(crate) mod chat;
+pub(crate) mod timeline_store;
+pub(crate) mod odometer;
+pub(crate) mod courier_summary;
+pub(crate) mod reassignment;
\ No newline at end of file
@@ -0,0 +1,59 @@
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use rdkafka::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use tokio::sync::mpsc;
+use tracing::error;
+use crate::models::alert::Alert;
+
+pub static ALERT_PUBLISHER: once_cell::sync::OnceCell<mpsc::Sender<Alert>> = once_cell::sync::OnceCell::new();
+
+pub static ALERT_METRICS: AlertMetrics = AlertMetrics {
+    dropped_alerts: AtomicU64::new(0),
+};
+
+pub struct AlertMetrics {
+    /// Alerts dropped because the publisher's queue was full or closed
+    pub dropped_alerts: AtomicU64,
+}
+
+impl AlertMetrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        format!(
+            "# TYPE order_alerts_dropped_total counter\n\
+             order_alerts_dropped_total {}\n",
+            self.dropped_alerts.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub struct AlertPublisher;
+
+impl AlertPublisher {
+    pub async fn run_actor() {
+        let broker = env::var("REDPANDA_BROKER").unwrap_or_else(|_| "localhost:19092".to_string());
+        let topic = env::var("ALERTS_TOPIC").unwrap_or_else(|_| "order_alerts".to_string());
+        let (tx, mut rx) = mpsc::channel(10_000);
+        ALERT_PUBLISHER.set(tx).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", broker.as_str())
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Producer creation error");
+
+        while let Some(alert) = rx.recv().await {
+            let payload = serde_json::to_string(&alert).unwrap();
+            if let Err((e, _)) = producer.send(
+                FutureRecord::to(topic.as_str())
+                    .payload(payload.as_bytes())
+                    .key(alert.order_id()), Duration::from_secs(0)).await {
+                error!("Failed to publish alert for order {}: {}", alert.order_id(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::Notify;
+use crate::handlers::protocol::Sequenced;
+use crate::models::chat::ChatRole;
+use crate::models::error::UpdateError;
+
+const REDACTED: &str = "[redacted]";
+
+pub struct ChatConfig {
+    /// Longest chat message accepted, in characters
+    pub max_length: usize,
+    /// Lowercase words masked in chat messages
+    pub blocked_words: HashSet<String>,
+    /// Whether phone numbers and email addresses are removed from chat messages
+    pub redact_contacts: bool,
+    /// Most chat messages relayed for an order
+    pub max_messages: usize,
+    /// Most chat messages and receipts queued for a participant whose socket didn't send them yet
+    pub max_queued: usize,
+}
+
+impl ChatConfig {
+    pub fn init() -> Self {
+        let number = |name: &str, default: usize| env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(default);
+
+        Self {
+            max_length: number("CHAT_MAX_LENGTH", 500),
+            blocked_words: env::var("CHAT_BLOCKED_WORDS")
+                .map(|words| words.split(',')
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty())
+                    .collect())
+                .unwrap_or_default(),
+            redact_contacts: env::var("CHAT_REDACT_CONTACTS")
+                .map(|s| s != "false")
+                .unwrap_or(true),
+            max_messages: number("CHAT_MAX_MESSAGES", 200),
+            max_queued: number("CHAT_MAX_QUEUED", 100),
+        }
+    }
+}
+
+pub static CHAT_CONFIG: Lazy<ChatConfig> = Lazy::new(ChatConfig::init);
+
+/// Checks chat messages before they are relayed, returning the text to relay or the reason the
+/// message is refused.
+pub trait ChatFilter: Send + Sync {
+    fn filter(&self, from: ChatRole, text: &str) -> Result<String, UpdateError>;
+}
+
+/// Filter applied to chat messages. Set it at startup to replace the default filter, which
+/// masks `CHAT_BLOCKED_WORDS` and redacts contact details.
+pub static CHAT_FILTER: OnceCell<Box<dyn ChatFilter>> = OnceCell::new();
+
+pub fn chat_filter() -> &'static dyn ChatFilter {
+    CHAT_FILTER.get_or_init(|| Box::new(DefaultChatFilter { config: &CHAT_CONFIG })).as_ref()
+}
+
+/// Chat message or receipt waiting for a participant's socket.
+#[derive(Clone, Debug)]
+pub struct QueuedChat {
+    pub message: Sequenced,
+    /// Id of the chat message this relays, reported to the order session once sent
+    pub relayed: Option<u64>,
+}
+
+/// Chat messages and receipts for a participant, kept until their socket sent them.
+///
+/// Other outbound messages only keep the latest one, chat messages are never replaced: a
+/// participant who wasn't connected, or whose socket failed to send them, gets them on reconnecting.
+/// Up to `capacity` of them are kept.
+#[derive(Clone)]
+pub struct ChatQueue {
+    messages: Arc<Mutex<VecDeque<QueuedChat>>>,
+    added: Arc<Notify>,
+    capacity: usize,
+}
+
+impl ChatQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self { messages: Arc::default(), added: Arc::default(), capacity }
+    }
+
+    /// Queues a message, returning false without queueing it if the queue is full.
+    pub fn push(&self, chat: QueuedChat) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            return false;
+        }
+        messages.push_back(chat);
+        self.added.notify_one();
+        true
+    }
+
+    /// Drops every message not sent yet.
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.messages.lock().unwrap().len() >= self.capacity
+    }
+
+    /// The oldest message not sent yet. It stays queued until `sent` is called.
+    pub fn front(&self) -> Option<QueuedChat> {
+        self.messages.lock().unwrap().front().cloned()
+    }
+
+    /// Removes the message returned by `front` once the socket sent it.
+    pub fn sent(&self) {
+        self.messages.lock().unwrap().pop_front();
+    }
+
+    /// Waits for a message to be pushed, returning at once if one was pushed since the last call.
+    pub async fn added(&self) {
+        self.added.notified().await
+    }
+}
+
+pub struct DefaultChatFilter {
+    config: &'static ChatConfig,
+}
+
+impl ChatFilter for DefaultChatFilter {
+    fn filter(&self, _from: ChatRole, text: &str) -> Result<String, UpdateError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(UpdateError::invalid_message("Empty chat message".to_string()));
+        }
+        if text.chars().count() > self.config.max_length {
+            return Err(UpdateError::invalid_message(
+                format!("Chat messages are limited to {} characters", self.config.max_length)));
+        }
+
+        let text = self.mask_blocked_words(text);
+        Ok(match self.config.redact_contacts {
+            true => redact_phone_numbers(&redact_emails(&text)),
+            false => text,
+        })
+    }
+}
+
+impl DefaultChatFilter {
+    fn mask_blocked_words(&self, text: &str) -> String {
+        if self.config.blocked_words.is_empty() {
+            return text.to_string();
+        }
+        text.split_inclusive(char::is_whitespace)
+            .map(|token| {
+                let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+                match self.config.blocked_words.contains(&word.to_lowercase()) {
+                    true if !word.is_empty() => token.replacen(word, &"*".repeat(word.chars().count()), 1),
+                    _ => token.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn redact_emails(text: &str) -> String {
+    text.split_inclusive(char::is_whitespace)
+        .map(|token| {
+            let word = token.trim_end();
+            let is_email = word.split_once('@')
+                .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'));
+            match is_email {
+                true => token.replacen(word, REDACTED, 1),
+                false => token.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Replaces runs of at least 7 digits, which may be separated by spaces, dashes, dots or brackets.
+fn redact_phone_numbers(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut redacted = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_ascii_digit() && chars[i] != '+' {
+            redacted.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        // The run ends at its last digit, so separators after it are kept
+        let (mut end, mut digits) = (i + 1, 0);
+        for (j, c) in chars.iter().enumerate().skip(i) {
+            match c {
+                c if c.is_ascii_digit() => {
+                    digits += 1;
+                    end = j + 1;
+                }
+                ' ' | '-' | '.' | '(' | ')' | '+' => {}
+                _ => break,
+            }
+        }
+        match digits >= 7 {
+            true => redacted.push_str(REDACTED),
+            false => redacted.extend(&chars[i..end]),
+        }
+        i = end;
+    }
+    redacted
+}
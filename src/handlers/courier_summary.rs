@@ -0,0 +1,228 @@
+use std::env;
+use std::time::Duration;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rdkafka::{ClientConfig, Message};
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use crate::handlers::incoming_order_processor::INSTANCE_ID;
+use crate::models::order_outcome::{CourierLegs, LegDistances, OutcomeReason, ORDER_COMPLETION_VERSION};
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+static SUMMARIES: Lazy<DashMap<(String, u64), CourierDailySummary>> = Lazy::new(DashMap::new);
+static COURIER_SUMMARY_RETENTION_DAYS: Lazy<u64> = Lazy::new(|| env::var("COURIER_SUMMARY_RETENTION_DAYS")
+    .ok()
+    .and_then(|s| s.parse::<u64>().ok())
+    .unwrap_or(35));
+
+/// Orders and distance of a courier on one UTC day, the inputs of courier pay.
+#[derive(Serialize, Clone)]
+pub struct CourierDailySummary {
+    pub courier_id: String,
+    /// `YYYY-MM-DD`
+    pub date: String,
+    /// Orders that ended while assigned to the courier
+    pub orders: u32,
+    pub delivered_orders: u32,
+    pub pickup_m: f64,
+    pub delivery_m: f64,
+    pub total_m: f64,
+}
+
+impl CourierDailySummary {
+    fn empty(courier_id: &str, day: u64) -> Self {
+        Self {
+            courier_id: courier_id.to_string(),
+            date: format_day(day),
+            orders: 0,
+            delivered_orders: 0,
+            pickup_m: 0.0,
+            delivery_m: 0.0,
+            total_m: 0.0,
+        }
+    }
+
+    fn add(&mut self, legs: &LegDistances) {
+        self.pickup_m += legs.pickup_m;
+        self.delivery_m += legs.delivery_m;
+        self.total_m += legs.total_m();
+    }
+}
+
+/// The fields of a `processed_orders` event the summaries add up.
+#[derive(Deserialize)]
+struct CompletedOrder {
+    version: u32,
+    courier_id: String,
+    reason: OutcomeReason,
+    completed_at: u64,
+    legs: LegDistances,
+    previous_couriers: Vec<CourierLegs>,
+}
+
+/// Adds up completed orders per courier and day, keeping the last `COURIER_SUMMARY_RETENTION_DAYS` days.
+///
+/// The summaries are built in memory from the `processed_orders` events of all instances, see
+/// `CourierSummaryProcessor`. They are best-effort: orders whose event left the topic's retention
+/// before the instance started are missing, and new orders show up once their event is read.
+pub struct CourierSummaryStore;
+
+impl CourierSummaryStore {
+    /// Counts the order for its last courier. Couriers it was reassigned away from are only
+    /// credited with the distance they travelled.
+    fn record(completion: &CompletedOrder) {
+        let day = completion.completed_at / MILLIS_PER_DAY;
+        for previous in &completion.previous_couriers {
+            SUMMARIES.entry((previous.courier_id.clone(), day))
+                .or_insert_with(|| CourierDailySummary::empty(&previous.courier_id, day))
+                .add(&previous.legs);
+        }
+        let mut summary = SUMMARIES.entry((completion.courier_id.clone(), day))
+            .or_insert_with(|| CourierDailySummary::empty(&completion.courier_id, day));
+        summary.orders += 1;
+        if completion.reason == OutcomeReason::Delivered {
+            summary.delivered_orders += 1;
+        }
+        summary.add(&completion.legs);
+        drop(summary);
+
+        let oldest = day.saturating_sub(*COURIER_SUMMARY_RETENTION_DAYS);
+        SUMMARIES.retain(|(_, day), _| *day >= oldest);
+    }
+
+    /// Summary of the courier on `date` (`YYYY-MM-DD`), `None` if the date is invalid.
+    pub fn get(courier_id: &str, date: &str) -> Option<CourierDailySummary> {
+        let day = parse_day(date)?;
+        Some(SUMMARIES.get(&(courier_id.to_string(), day))
+            .map(|summary| summary.clone())
+            .unwrap_or_else(|| CourierDailySummary::empty(courier_id, day)))
+    }
+
+    pub fn today(unix_millis: u64) -> String {
+        format_day(unix_millis / MILLIS_PER_DAY)
+    }
+}
+
+/// Reads `processed_orders` into the `CourierSummaryStore`.
+///
+/// Every instance reads the whole topic, from the start on every launch, as the summaries
+/// aren't persisted.
+pub struct CourierSummaryProcessor;
+
+impl CourierSummaryProcessor {
+    pub async fn run_actor() {
+        let broker = env::var("REDPANDA_BROKER").unwrap_or_else(|_| "localhost:19092".to_string());
+        let group_id = env::var("COURIER_SUMMARY_GROUP_ID").unwrap_or_else(|_| format!(
+            "geolocation_services_courier_summaries_{}",
+            INSTANCE_ID.as_str()));
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+
+        info!("Starting courier summary processor");
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", broker.as_str())
+            .set("group.id", group_id.as_str())
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            // Offsets aren't committed, so the summaries are rebuilt from the start of the topic
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .set_log_level(RDKafkaLogLevel::Debug)
+            .create()
+            .unwrap();
+
+        consumer
+            .subscribe(&["processed_orders"])
+            .expect("Can't subscribe to specified topics");
+
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Error reading processed order: {}", e);
+                    continue;
+                }
+            };
+            match msg.payload_view::<str>().map(|payload| payload.map(serde_json::from_str::<CompletedOrder>)) {
+                Some(Ok(Ok(completion))) if completion.version == ORDER_COMPLETION_VERSION =>
+                    CourierSummaryStore::record(&completion),
+                // Events from before the versioned event, or of a version this instance doesn't know
+                _ => debug!("Skipping processed order at offset {}", msg.offset()),
+            }
+        }
+    }
+}
+
+/// Formats days since the Unix epoch as a `YYYY-MM-DD` date.
+fn format_day(day: u64) -> String {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Parses a `YYYY-MM-DD` date into days since the Unix epoch.
+fn parse_day(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (y, m, d) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    // Days from civil, the inverse of `format_day`
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    u64::try_from(era * 146_097 + doe - 719_468).ok()
+        .filter(|day| format_day(*day) == date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_day, parse_day};
+
+    #[test]
+    fn formats_and_parses_days_across_month_year_and_leap_day_boundaries() {
+        let days = [
+            ("1970-01-01", 0),
+            ("2000-02-29", 11_016),
+            ("2000-03-01", 11_017),
+            ("2023-12-31", 19_722),
+            ("2024-01-01", 19_723),
+            ("2024-02-29", 19_782),
+            ("2100-02-28", 47_540),
+            ("2100-03-01", 47_541),
+        ];
+        for (date, day) in days {
+            assert_eq!(format_day(day), date);
+            assert_eq!(parse_day(date), Some(day), "{}", date);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_day_for_a_few_centuries() {
+        for day in 0..150_000 {
+            assert_eq!(parse_day(&format_day(day)), Some(day));
+        }
+    }
+
+    #[test]
+    fn rejects_days_that_dont_exist() {
+        for date in ["2023-02-29", "2100-02-29", "2024-04-31", "2024-13-01", "2024-00-10", "2024-1-01", "1969-12-31", "today"] {
+            assert_eq!(parse_day(date), None, "{}", date);
+        }
+    }
+}
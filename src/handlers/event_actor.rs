@@ -1,110 +1,666 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use serde_json::Value;
 use tokio::select;
-use tokio::sync::{mpsc, watch};
-use tracing::log::{debug, error, info};
-use crate::handlers::events::{Command, StateKind};
-use crate::handlers::handler::{UpdateHandler, WebSocketUpdateHandler};
-use crate::handlers::location_logger::LOCATION_LOGGER;
-use crate::models::updates::{OrderDelivered, OrderCreated, OrderInTransit, OrderState};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::log::{debug, error, warn};
+use crate::handlers::chat::{chat_filter, CHAT_CONFIG, ChatQueue, QueuedChat};
+use crate::handlers::events::Command;
+use crate::handlers::handler::FrameHandler;
+use crate::handlers::odometer::{ODOMETER_CONFIG, Odometer};
+use crate::handlers::protocol::{Inbound, OutboundMessage, Sequenced};
+use crate::handlers::reply_cache::{REPLY_CACHE_CONFIG, ReplyCache};
+use crate::handlers::state_machine::OrderContext;
+use crate::handlers::states::StateKind;
+use crate::handlers::throttle::{PositionThrottle, ThrottleConfig};
+use crate::handlers::timeline_store::TimelineStore;
+use crate::models::chat::{ChatEntry, ChatReceipt, ChatRole, InboundChatUpdate, OutboundChatUpdate};
+use crate::models::error::{ErrorCode, UpdateError};
+use crate::models::order_outcome::{unix_millis, CourierLegs, OrderOutcome, OutcomeReason, StateTransition};
+use crate::models::position::Position;
+use crate::models::reassignment::{CourierAssignment, OutboundReassignmentUpdate};
+use crate::models::timeline::{Actor, TimelineEntry, TimelineEvent};
+
+/// Operator commands sent through the admin API.
+pub enum AdminCommand {
+    /// Moves the order to a state, whether or not the current state may move there.
+    Transition(StateKind, oneshot::Sender<Result<(), String>>),
+    /// Hands the order to another courier, answering with the state the order was reset to.
+    Reassign(CourierAssignment, oneshot::Sender<Result<StateKind, String>>),
+    /// Ends the session before the order completes.
+    Terminate,
+}
+
+/// What the admin API shows of a running session.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionStatus {
+    pub state: StateKind,
+    /// Milliseconds since the Unix epoch of the last message from a participant, or of the session start
+    pub last_activity: u64,
+}
+
+/// Message from a participant's socket, along with the user the socket was opened by.
+#[derive(Debug)]
+pub struct SocketMessage {
+    pub user_id: Arc<String>,
+    pub inbound: Inbound,
+}
+
+enum Message {
+    Customer(Inbound),
+    Courier(Inbound),
+}
+
+/// Messages to a participant, numbered in the order they are produced.
+pub struct Outbox {
+    sender: watch::Sender<Option<Sequenced>>,
+    /// Chat messages and receipts, which are queued instead of replacing each other
+    chat: ChatQueue,
+    /// Last number given out, shared with the participant's sockets for the replies they send themselves
+    seq: Arc<AtomicU64>,
+}
+
+impl Outbox {
+    pub fn new(sender: watch::Sender<Option<Sequenced>>) -> Self {
+        Self { sender, chat: ChatQueue::new(CHAT_CONFIG.max_queued), seq: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn chat(&self) -> ChatQueue {
+        self.chat.clone()
+    }
+
+    pub fn seq(&self) -> Arc<AtomicU64> {
+        self.seq.clone()
+    }
+
+    fn next(&self, message: OutboundMessage) -> Sequenced {
+        Sequenced { seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1, message }
+    }
+
+    fn send(&mut self, message: OutboundMessage) {
+        self.sender.send(Some(self.next(message))).unwrap();
+    }
+
+    /// Queues a chat message or receipt, `relayed` being the id of the chat message it relays.
+    /// Chat messages are only relayed when there's room, a receipt that doesn't fit is dropped.
+    fn queue(&mut self, message: OutboundMessage, relayed: Option<u64>) {
+        if !self.chat.push(QueuedChat { message: self.next(message), relayed }) {
+            warn!("Dropping chat receipt, the participant's chat queue is full");
+        }
+    }
+
+    /// The last message sent, which replaced any earlier one a socket didn't take yet.
+    fn last(&self) -> Option<OutboundMessage> {
+        self.sender.borrow().as_ref().map(|sent| sent.message.clone())
+    }
+}
 
 pub struct EventActor {
-    order_id: Arc<String>,
-    inbound_customer: mpsc::Receiver<String>,
-    inbound_courier: mpsc::Receiver<String>,
-    outbound_customer: watch::Sender<String>,
-    outbound_courier: watch::Sender<String>,
-    handler: Box<dyn UpdateHandler<String> + Sync + Send>,
+    order: OrderContext,
+    inbound_customer: mpsc::Receiver<SocketMessage>,
+    inbound_courier: mpsc::Receiver<SocketMessage>,
+    outbound_customer: Outbox,
+    outbound_courier: Outbox,
+    admin: mpsc::Receiver<AdminCommand>,
+    /// Ids of chat messages a socket sent to the other participant
+    delivered: mpsc::UnboundedReceiver<u64>,
+    status: watch::Sender<SessionStatus>,
+    handler: Box<dyn FrameHandler>,
     current_state: StateKind,
+    customer_replies: ReplyCache,
+    courier_replies: ReplyCache,
+    /// Thins out the courier positions recorded in the timeline, with the state's log cadence
+    timeline_throttle: PositionThrottle,
+    outcome: OrderOutcome,
 }
 
 impl EventActor {
-    pub fn new(order_id: Arc<String>,
-               inbound_customer: mpsc::Receiver<String>,
-               inbound_courier: mpsc::Receiver<String>,
-               outbound_customer: watch::Sender<String>,
-               outbound_courier: watch::Sender<String>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(order: OrderContext,
+               inbound_customer: mpsc::Receiver<SocketMessage>,
+               inbound_courier: mpsc::Receiver<SocketMessage>,
+               outbound_customer: Outbox,
+               outbound_courier: Outbox,
+               admin: mpsc::Receiver<AdminCommand>,
+               delivered: mpsc::UnboundedReceiver<u64>,
+               status: watch::Sender<SessionStatus>) -> Self {
+        let initial = order.workflow.initial();
         Self {
-            order_id,
+            handler: initial.enter(&order),
+            outcome: OrderOutcome::new(&order.order_info, unix_millis()),
+            current_state: initial,
+            order,
             inbound_customer,
             inbound_courier,
             outbound_customer,
             outbound_courier,
-            handler: Box::new(WebSocketUpdateHandler::<OrderCreated>::new(OrderCreated{})),
-            current_state: StateKind::OrderCreated,
+            admin,
+            delivered,
+            status,
+            customer_replies: ReplyCache::new(&REPLY_CACHE_CONFIG),
+            courier_replies: ReplyCache::new(&REPLY_CACHE_CONFIG),
+            timeline_throttle: PositionThrottle::new(ThrottleConfig::for_state(&initial.to_string())),
         }
     }
 
-    pub async fn run_actor(mut self) {
-        enum Message {
-            Customer(String),
-            Courier(String),
-        }
+    pub async fn run_actor(mut self) -> OrderOutcome {
+        self.record(Actor::System, TimelineEntry::SessionStarted);
         loop {
             let message = select! {
-                message = self.inbound_customer.recv() => message.map(Message::Customer),
-                message = self.inbound_courier.recv() => message.map(Message::Courier),
+                message = self.inbound_customer.recv() => message.map(|message| Message::Customer(message.inbound)),
+                message = self.inbound_courier.recv() => match message {
+                    // Sent by the socket of a courier the order was taken from, before it was closed
+                    Some(message) if *message.user_id != self.order.order_info.courier_id => {
+                        debug!("Dropping message of previous courier {} of order {}", message.user_id, self.order.order_id);
+                        continue;
+                    }
+                    message => message.map(|message| Message::Courier(message.inbound)),
+                },
+                command = self.admin.recv() => match command {
+                    Some(AdminCommand::Transition(state, reply)) => {
+                        reply.send(self.force_transition(state)).ok();
+                        continue;
+                    }
+                    Some(AdminCommand::Reassign(assignment, reply)) => {
+                        reply.send(self.reassign(assignment)).ok();
+                        continue;
+                    }
+                    Some(AdminCommand::Terminate) => return self.terminate(),
+                    None => None,
+                },
+                Some(message_id) = self.delivered.recv() => {
+                    self.confirm_delivery(message_id);
+                    continue;
+                }
             };
 
             match message {
                 Some(message) => {
-                    let commands = match message {
-                        Message::Customer(message) => self.handler.inbound_customer_update(message).await,
-                        Message::Courier(message) => self.handler.inbound_courier_update(message).await,
+                    self.status.send_modify(|status| status.last_activity = unix_millis());
+                    if self.replay(&message) {
+                        continue;
+                    }
+                    // Only replies to accepted messages are remembered, a rejected message may be accepted when retried
+                    if let Some((from, id, text)) = Self::chat_message(&message) {
+                        self.relay_chat(from, text, id);
+                        continue;
+                    }
+                    let (actor, update) = match &message {
+                        Message::Customer(inbound) => (Actor::Customer, inbound),
+                        Message::Courier(inbound) => (Actor::Courier, inbound),
+                    };
+                    let update = update.update.as_ref().ok()
+                        .and_then(|frame| frame.parse::<Value>().ok())
+                        .map(Self::redact);
+                    // Ids of the message being handled, echoed in the messages answering it
+                    let (customer_msg_id, courier_msg_id, commands) = match message {
+                        Message::Customer(Inbound { id, update: Ok(message) }) =>
+                            (id, None, self.handler.inbound_customer_frame(message).await),
+                        Message::Courier(Inbound { id, update: Ok(message) }) =>
+                            (None, id, self.handler.inbound_courier_frame(message).await),
+                        Message::Customer(Inbound { id, update: Err(e) }) =>
+                            (id, None, vec![Command::CustomerError(serde_json::to_value(e).unwrap())]),
+                        Message::Courier(Inbound { id, update: Err(e) }) =>
+                            (None, id, vec![Command::CourierError(serde_json::to_value(e).unwrap())]),
                     };
+                    let mut rejected = commands.iter()
+                        .any(|command| matches!(command, Command::CustomerError(_) | Command::CourierError(_)));
+                    let recorded = self.records_update(actor, &update, &commands);
 
                     for command in commands {
+                        if recorded {
+                            self.record_command(actor, &update, &command);
+                        }
                         match command {
                             Command::SendCourierNotify(msg) => self.send_courier_update(msg),
                             Command::SendCustomerNotify(msg) => self.send_customer_update(msg),
-                            Command::Transition(tr) => self.transition(tr),
-                            Command::ProcessedCourierUpdate => self.outbound_courier.send("\"PROCESSED\"".to_string()).unwrap(),
-                            Command::ProcessedCustomerUpdate => self.outbound_customer.send("\"PROCESSED\"".to_string()).unwrap(),
-                            Command::CustomerError(e) => self.outbound_customer.send(e.to_string()).unwrap(),
-                            Command::CourierError(e) => self.outbound_courier.send(e.to_string()).unwrap(),
+                            Command::Transition(tr) =>
+                                rejected |= !self.transition(tr, actor, customer_msg_id.clone(), courier_msg_id.clone()),
+                            Command::ProcessedCourierUpdate =>
+                                self.outbound_courier.send(OutboundMessage::Processed { id: courier_msg_id.clone() }),
+                            Command::ProcessedCustomerUpdate =>
+                                self.outbound_customer.send(OutboundMessage::Processed { id: customer_msg_id.clone() }),
+                            Command::CustomerError(e) => self.send_customer_error(e, customer_msg_id.clone()),
+                            Command::CourierError(e) => self.send_courier_error(e, courier_msg_id.clone()),
+                            Command::RecordProof(proof) => self.outcome.proof = Some(proof),
+                            Command::RecordDistance(leg, distance) => self.outcome.legs.add(leg, distance),
                             Command::OrderComplete => {
-                                self.outbound_customer.send("\"ORDER_COMPLETE\"".to_string()).unwrap();
-                                self.outbound_courier.send("\"ORDER_COMPLETE\"".to_string()).unwrap();
-                                return;
+                                self.record(actor, TimelineEntry::OrderComplete);
+                                self.outbound_customer.send(OutboundMessage::OrderComplete);
+                                self.outbound_courier.send(OutboundMessage::OrderComplete);
+                                return self.finish(OutcomeReason::Delivered);
                             }
                         }
                     }
+                    if !rejected {
+                        self.remember_replies(customer_msg_id.as_deref(), courier_msg_id.as_deref());
+                    }
                 }
                 None => {
                     debug!("Channel closed");
-                    return;
+                    return self.finish(OutcomeReason::SessionClosed);
                 }
             }
         }
     }
 
-    fn send_customer_update(&mut self, mut msg: serde_json::Value) {
-        msg["order_state"] = serde_json::Value::String(self.current_state.to_string());
-        self.outbound_customer.send(msg.to_string()).unwrap();
+    /// Ends the session on an operator's behalf, telling both participants it is over.
+    fn terminate(mut self) -> OrderOutcome {
+        self.record(Actor::Admin, TimelineEntry::SessionTerminated);
+        self.outbound_customer.send(OutboundMessage::OrderComplete);
+        self.outbound_courier.send(OutboundMessage::OrderComplete);
+        self.finish(OutcomeReason::Terminated)
     }
 
-    fn send_courier_update(&mut self, mut msg: serde_json::Value) {
-        msg["order_state"] = serde_json::Value::String(self.current_state.to_string());
-        self.outbound_courier.send(msg.to_string()).unwrap();
+    fn finish(mut self, reason: OutcomeReason) -> OrderOutcome {
+        self.outcome.reason = reason;
+        self.outcome.final_state = Some(self.current_state.to_string());
+        self.outcome
     }
 
-    fn transition(&mut self, tr: StateKind) {
-        debug!("Transitioning to {:?}", tr);
-        let new_handler: Box<dyn UpdateHandler<String> + Sync + Send> = match &tr {
-            StateKind::OrderCreated => Box::new(WebSocketUpdateHandler::<OrderCreated>::new(OrderCreated { })),
-            StateKind::OrderInTransit => Box::new(WebSocketUpdateHandler::<OrderInTransit>::new(OrderInTransit{
-                order_id: self.order_id.clone(),
-                logger: LOCATION_LOGGER.get().unwrap().clone(),
-            })),
-            StateKind::OrderDelivered => Box::new(WebSocketUpdateHandler::<OrderDelivered>::new(OrderDelivered{})),
+    /// Answers a message the participant already sent with the reply it got, returning whether it did.
+    fn replay(&mut self, message: &Message) -> bool {
+        let (replies, outbound, id) = match message {
+            Message::Customer(Inbound { id: Some(id), .. }) => (&self.customer_replies, &mut self.outbound_customer, id),
+            Message::Courier(Inbound { id: Some(id), .. }) => (&self.courier_replies, &mut self.outbound_courier, id),
+            _ => return false,
+        };
+        match replies.get(id) {
+            Some(reply) => {
+                debug!("Replaying reply to duplicate message {} of order {}", id, self.order.order_id);
+                match reply {
+                    OutboundMessage::ChatReceipt { .. } => outbound.queue(reply.clone(), None),
+                    _ => outbound.send(reply.clone()),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The sender, id and text of a chat message, which is handled the same way in every state.
+    fn chat_message(message: &Message) -> Option<(ChatRole, Option<String>, String)> {
+        let (from, Inbound { id, update: Ok(frame) }) = (match message {
+            Message::Customer(inbound) => (ChatRole::Customer, inbound),
+            Message::Courier(inbound) => (ChatRole::Courier, inbound),
+        }) else {
+            return None;
+        };
+        match frame.parse::<InboundChatUpdate>() {
+            Ok(InboundChatUpdate::ChatMessage(chat)) => Some((from, id.clone(), chat.text)),
+            Err(_) => None,
+        }
+    }
+
+    /// Queues a chat message for the other participant and answers the sender with a receipt,
+    /// which is remembered unless the message was refused.
+    fn relay_chat(&mut self, from: ChatRole, text: String, id: Option<String>) {
+        let (sender, recipient) = match from {
+            ChatRole::Customer => (&self.outbound_customer, &self.outbound_courier),
+            ChatRole::Courier => (&self.outbound_courier, &self.outbound_customer),
+        };
+        let text = if self.current_state.transitions().is_empty() {
+            Err(UpdateError::not_allowed_in_state(&self.current_state.to_string()))
+        } else if self.outcome.chat.len() >= CHAT_CONFIG.max_messages {
+            Err(UpdateError::new(ErrorCode::ChatLimitReached,
+                format!("Orders are limited to {} chat messages", CHAT_CONFIG.max_messages)))
+        } else if recipient.chat.is_full() || sender.chat.is_full() {
+            Err(UpdateError::new(ErrorCode::ChatLimitReached, "Too many chat messages waiting for delivery".to_string()))
+        } else {
+            chat_filter().filter(from, &text)
+        };
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                let error = serde_json::to_value(e).unwrap();
+                self.record(from.into(), TimelineEntry::UpdateRejected { error: error.clone() });
+                return match from {
+                    ChatRole::Customer => self.send_customer_error(error, id),
+                    ChatRole::Courier => self.send_courier_error(error, id),
+                };
+            }
+        };
+
+        let entry = ChatEntry {
+            message_id: self.outcome.chat.len() as u64 + 1,
+            from,
+            text,
+            timestamp: unix_millis(),
+        };
+        self.record(from.into(), TimelineEntry::ChatRelayed { message_id: entry.message_id });
+        let (sender, replies, recipient) = match from {
+            ChatRole::Customer => (&mut self.outbound_customer, &mut self.customer_replies, &mut self.outbound_courier),
+            ChatRole::Courier => (&mut self.outbound_courier, &mut self.courier_replies, &mut self.outbound_customer),
+        };
+        recipient.queue(OutboundMessage::Update {
+            update: serde_json::to_value(OutboundChatUpdate::ChatMessage(entry.clone())).unwrap(),
+            order_state: self.current_state.to_string(),
+        }, Some(entry.message_id));
+        let receipt = ChatReceipt { message_id: entry.message_id, delivered: false };
+        let receipt = OutboundMessage::ChatReceipt { receipt, id };
+        replies.record(&receipt);
+        sender.queue(receipt, None);
+        self.outcome.chat.push(entry);
+    }
+
+    /// Tells the sender of a chat message that the other participant's socket sent it.
+    fn confirm_delivery(&mut self, message_id: u64) {
+        let Some(from) = self.outcome.chat.iter().find(|entry| entry.message_id == message_id).map(|entry| entry.from) else {
+            return;
+        };
+        let receipt = OutboundMessage::ChatReceipt { receipt: ChatReceipt { message_id, delivered: true }, id: None };
+        match from {
+            ChatRole::Customer => self.outbound_customer.queue(receipt, None),
+            ChatRole::Courier => self.outbound_courier.queue(receipt, None),
+        }
+    }
+
+    /// Appends an event to the order's timeline.
+    fn record(&self, actor: Actor, entry: TimelineEntry) {
+        TimelineStore::record(&self.order.order_id, TimelineEvent {
+            timestamp: unix_millis(),
+            actor,
+            state: self.current_state.to_string(),
+            entry,
+        });
+    }
+
+    /// Hides the content of submitted proofs, which may be the handoff PIN. `ProofRecorded`
+    /// records the accepted proof instead.
+    fn redact(mut update: Value) -> Value {
+        if let Some(proof) = update.get_mut("SubmitProof") {
+            *proof = Value::String("[redacted]".to_string());
+        }
+        update
+    }
+
+    /// Whether the commands run for an update of `actor` are recorded. Courier positions are only
+    /// recorded when the timeline's throttle lets them through, or when they lead to more than
+    /// their acknowledgement, so the timeline isn't flooded with them.
+    fn records_update(&mut self, actor: Actor, update: &Option<Value>, commands: &[Command]) -> bool {
+        let Some(pos) = update.as_ref()
+            .filter(|_| actor == Actor::Courier)
+            .and_then(|update| update.get("InTransit"))
+            .and_then(|pos| serde_json::from_value::<Position>(pos.clone()).ok()) else {
+            return true;
+        };
+        self.timeline_throttle.should_log(&pos, Instant::now())
+            || commands.iter().any(|command| !matches!(command, Command::ProcessedCourierUpdate | Command::RecordDistance(..)))
+    }
+
+    /// Records a command run for an update of `actor`. Transitions are recorded once they happen.
+    fn record_command(&self, actor: Actor, update: &Option<Value>, command: &Command) {
+        let entry = match command {
+            Command::SendCourierNotify(update) => TimelineEntry::Notified { recipient: Actor::Courier, update: update.clone() },
+            Command::SendCustomerNotify(update) => TimelineEntry::Notified { recipient: Actor::Customer, update: update.clone() },
+            Command::ProcessedCourierUpdate | Command::ProcessedCustomerUpdate =>
+                TimelineEntry::UpdateProcessed { update: update.clone() },
+            Command::CourierError(error) | Command::CustomerError(error) => TimelineEntry::UpdateRejected { error: error.clone() },
+            Command::RecordProof(proof) => TimelineEntry::ProofRecorded { proof: proof.clone() },
+            // Distances add up to the outcome, the positions they come from are recorded already
+            Command::Transition(_) | Command::RecordDistance(..) | Command::OrderComplete => return,
+        };
+        self.record(actor, entry);
+    }
+
+    /// Records the last message sent to each participant if it answers the message with the given id.
+    fn remember_replies(&mut self, customer_msg_id: Option<&str>, courier_msg_id: Option<&str>) {
+        if let Some(reply) = self.outbound_customer.last().filter(|reply| reply.id() == customer_msg_id) {
+            self.customer_replies.record(&reply);
+        }
+        if let Some(reply) = self.outbound_courier.last().filter(|reply| reply.id() == courier_msg_id) {
+            self.courier_replies.record(&reply);
+        }
+    }
+
+    fn send_customer_update(&mut self, msg: serde_json::Value) {
+        let msg = OutboundMessage::Update { update: msg, order_state: self.current_state.to_string() };
+        self.outbound_customer.send(msg);
+    }
+
+    fn send_courier_update(&mut self, msg: serde_json::Value) {
+        let msg = OutboundMessage::Update { update: msg, order_state: self.current_state.to_string() };
+        self.outbound_courier.send(msg);
+    }
+
+    fn send_customer_error(&mut self, error: serde_json::Value, id: Option<String>) {
+        let msg = OutboundMessage::Error { error, order_state: self.current_state.to_string(), id };
+        self.outbound_customer.send(msg);
+    }
+
+    fn send_courier_error(&mut self, error: serde_json::Value, id: Option<String>) {
+        let msg = OutboundMessage::Error { error, order_state: self.current_state.to_string(), id };
+        self.outbound_courier.send(msg);
+    }
+
+    /// Moves to state `tr`, echoing the id of the customer or courier message that caused it.
+    /// A transition the state doesn't declare is refused to the participant, returning false.
+    fn transition(&mut self, tr: StateKind, actor: Actor, customer_msg_id: Option<String>, courier_msg_id: Option<String>) -> bool {
+        let tr = self.order.workflow.next(self.current_state, tr);
+        if !self.current_state.can_transition_to(tr) {
+            error!("Order {} can't move from {} to {}", self.order.order_id, self.current_state, tr);
+            let error = serde_json::to_value(UpdateError::not_allowed_in_state(&self.current_state.to_string())).unwrap();
+            self.record(actor, TimelineEntry::UpdateRejected { error: error.clone() });
+            match actor {
+                Actor::Customer => self.send_customer_error(error, customer_msg_id),
+                Actor::Courier => self.send_courier_error(error, courier_msg_id),
+                Actor::System | Actor::Admin => (),
+            }
+            return false;
+        }
+        self.enter(tr, actor, customer_msg_id, courier_msg_id);
+        true
+    }
+
+    /// Moves to state `tr` on an operator's behalf, skipping the checks of `transition`.
+    fn force_transition(&mut self, tr: StateKind) -> Result<(), String> {
+        if tr == self.current_state {
+            return Err(format!("Order is already in state {}", tr));
+        }
+        self.enter(tr, Actor::Admin, None, None);
+        Ok(())
+    }
+
+    /// Hands the order to another courier and moves it back to where the new courier starts:
+    /// the pickup if the order wasn't picked up yet, otherwise the start of the transit, as the
+    /// new courier collects it from the previous one.
+    fn reassign(&mut self, assignment: CourierAssignment) -> Result<StateKind, String> {
+        if self.current_state.transitions().is_empty() {
+            return Err(format!("Order can't be reassigned in state {}", self.current_state));
+        }
+        let previous = &self.order.order_info.courier_id;
+        if *previous == assignment.courier_id {
+            return Err(format!("Order is already assigned to courier {}", previous));
+        }
+        self.record(Actor::Admin, TimelineEntry::CourierReassigned {
+            from: previous.clone(),
+            to: assignment.courier_id.clone(),
+            reason: assignment.reason.clone(),
+        });
+        // The distance so far is the previous courier's, the new one starts from zero
+        self.outcome.previous_couriers.push(CourierLegs {
+            courier_id: previous.clone(),
+            legs: std::mem::take(&mut self.outcome.legs),
+        });
+        let mut order_info = (*self.order.order_info).clone();
+        order_info.courier_id = assignment.courier_id.clone();
+        self.order.order_info = Arc::new(order_info);
+        self.outcome.courier_id = assignment.courier_id.clone();
+        // The new courier's distance counts from their own first position
+        self.order.odometer = Arc::new(Mutex::new(Odometer::new(&ODOMETER_CONFIG)));
+        // Replies and chat messages meant for the previous courier aren't the new one's
+        self.courier_replies = ReplyCache::new(&REPLY_CACHE_CONFIG);
+        self.outbound_courier.chat.clear();
+
+        let state = match self.current_state {
+            StateKind::OrderCreated | StateKind::SubstitutionApproval => self.order.workflow.initial(),
+            _ => StateKind::OrderInTransit,
         };
-        let transition_msg = serde_json::json!({
-            "transition": tr.to_string()
-        }).to_string();
+        self.enter(state, Actor::Admin, None, None);
+        // Sent after the transition, which it would otherwise replace, and carrying the new state
+        self.outbound_customer.send(OutboundMessage::Update {
+            update: serde_json::to_value(OutboundReassignmentUpdate::CourierReassigned(assignment)).unwrap(),
+            order_state: state.to_string(),
+        });
+        Ok(state)
+    }
 
-        self.handler = new_handler;
+    fn enter(&mut self, tr: StateKind, actor: Actor, customer_msg_id: Option<String>, courier_msg_id: Option<String>) {
+        debug!("Transitioning to {:?}", tr);
+        let state = tr.to_string();
+        self.record(actor, TimelineEntry::Transition { from: self.current_state.to_string(), to: state.clone() });
+        self.outcome.transitions.push(StateTransition {
+            from: self.current_state.to_string(),
+            to: state.clone(),
+            timestamp: unix_millis(),
+        });
+
+        self.handler = tr.enter(&self.order);
+        self.timeline_throttle = PositionThrottle::new(ThrottleConfig::for_state(&state));
         self.current_state = tr;
+        self.status.send_modify(|status| status.state = tr);
+
+        self.outbound_customer.send(OutboundMessage::Transition { state: state.clone(), id: customer_msg_id });
+        self.outbound_courier.send(OutboundMessage::Transition { state, id: courier_msg_id });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::AtomicU32;
+    use serde_json::json;
+    use tokio::sync::{mpsc, oneshot, watch};
+    use crate::handlers::chat::QueuedChat;
+    use crate::handlers::odometer::{ODOMETER_CONFIG, Odometer};
+    use crate::handlers::protocol::{Inbound, InboundFrame, OutboundMessage, Sequenced};
+    use crate::handlers::state_machine::OrderContext;
+    use crate::handlers::states::StateKind;
+    use crate::handlers::workflow::Workflow;
+    use crate::models::order_info::OrderInfo;
+    use crate::models::order_outcome::{unix_millis, Leg};
+    use crate::models::position::Distance;
+    use crate::models::reassignment::CourierAssignment;
+    use super::{AdminCommand, EventActor, Outbox, SessionStatus, SocketMessage};
+
+    /// The ends of a session's channels its sockets and the admin API hold.
+    struct Session {
+        customer: mpsc::Sender<SocketMessage>,
+        courier: mpsc::Sender<SocketMessage>,
+        outbound_customer: watch::Receiver<Option<Sequenced>>,
+        outbound_courier: watch::Receiver<Option<Sequenced>>,
+        admin: mpsc::Sender<AdminCommand>,
+        status: watch::Receiver<SessionStatus>,
+        _delivered: mpsc::UnboundedSender<u64>,
+    }
+
+    fn session(order_id: &str) -> (EventActor, Session) {
+        let order_info = OrderInfo {
+            order_id: order_id.to_string(),
+            customer_id: "customer".to_string(),
+            courier_id: "courier-1".to_string(),
+            restaurant: None,
+            destination: None,
+            route: None,
+            workflow: None,
+        };
+        let order = OrderContext {
+            order_id: Arc::new(order_id.to_string()),
+            order_info: Arc::new(order_info),
+            workflow: Workflow::get(None),
+            pin: None,
+            pin_attempts: Arc::new(AtomicU32::new(0)),
+            odometer: Arc::new(Mutex::new(Odometer::new(&ODOMETER_CONFIG))),
+        };
+        let (customer, customer_recv) = mpsc::channel(8);
+        let (courier, courier_recv) = mpsc::channel(8);
+        let (outbound_customer_send, outbound_customer) = watch::channel(None);
+        let (outbound_courier_send, outbound_courier) = watch::channel(None);
+        let (admin, admin_recv) = mpsc::channel(8);
+        let (delivered, delivered_recv) = mpsc::unbounded_channel();
+        let (status_send, status) = watch::channel(SessionStatus { state: StateKind::OrderCreated, last_activity: unix_millis() });
+        let actor = EventActor::new(order, customer_recv, courier_recv, Outbox::new(outbound_customer_send),
+                                    Outbox::new(outbound_courier_send), admin_recv, delivered_recv, status_send);
+        (actor, Session { customer, courier, outbound_customer, outbound_courier, admin, status, _delivered: delivered })
+    }
+
+    fn assignment(courier_id: &str) -> CourierAssignment {
+        CourierAssignment { courier_id: courier_id.to_string(), reason: "Vehicle breakdown".to_string() }
+    }
 
-        self.outbound_customer.send(transition_msg.clone()).unwrap();
-        self.outbound_courier.send(transition_msg).unwrap();
+    fn courier_message(courier_id: &str, id: &str, update: &str) -> SocketMessage {
+        SocketMessage {
+            user_id: Arc::new(courier_id.to_string()),
+            inbound: Inbound { id: Some(id.to_string()), update: Ok(InboundFrame::Json(update.to_string())) },
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reassigning_keeps_the_distance_with_the_previous_courier_and_forgets_their_replies() {
+        let (mut actor, session) = session("reassign-test-1");
+        actor.outcome.legs.add(Leg::Pickup, Distance { km: 1.5 });
+        actor.courier_replies.record(&OutboundMessage::Processed { id: Some("9".to_string()) });
+        actor.outbound_courier.queue(OutboundMessage::OrderComplete, None);
+
+        assert_eq!(actor.reassign(assignment("courier-2")), Ok(StateKind::OrderCreated));
+
+        assert_eq!(actor.order.order_info.courier_id, "courier-2");
+        assert_eq!(actor.outcome.courier_id, "courier-2");
+        assert_eq!(actor.outcome.previous_couriers.len(), 1);
+        assert_eq!(actor.outcome.previous_couriers[0].courier_id, "courier-1");
+        assert_eq!(actor.outcome.previous_couriers[0].legs.pickup_m, 1500.0);
+        assert_eq!(actor.outcome.legs.pickup_m, 0.0);
+        assert!(actor.courier_replies.get("9").is_none());
+        assert!(actor.outbound_courier.chat.front().is_none());
+
+        let Some(Sequenced { message: OutboundMessage::Update { update, order_state }, .. }) = session.outbound_customer.borrow().clone() else {
+            panic!("The customer wasn't told about the reassignment");
+        };
+        assert_eq!(update, json!({ "CourierReassigned": { "courier_id": "courier-2", "reason": "Vehicle breakdown" } }));
+        assert_eq!(order_state, "OrderCreated");
+    }
+
+    #[test]
+    fn refuses_to_reassign_to_the_same_courier_or_a_delivered_order() {
+        let (mut actor, _session) = session("reassign-test-2");
+        assert!(actor.reassign(assignment("courier-1")).is_err());
+
+        actor.force_transition(StateKind::OrderDelivered).unwrap();
+        assert!(actor.reassign(assignment("courier-2")).is_err());
+        assert!(actor.outcome.previous_couriers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_messages_of_the_previous_courier() {
+        let (actor, mut session) = session("reassign-test-3");
+        let actor = tokio::spawn(actor.run_actor());
+
+        let (reply, result) = oneshot::channel();
+        session.admin.send(AdminCommand::Reassign(assignment("courier-2"), reply)).await.ok();
+        assert_eq!(result.await.unwrap(), Ok(StateKind::OrderCreated));
+
+        // Still queued from the previous courier's socket, it would take the order if handled
+        session.courier.send(courier_message("courier-1", "1", r#""TookOrder""#)).await.unwrap();
+        session.courier.send(courier_message("courier-2", "2", r#"{"InTransit":{"lat":52.5,"lon":13.4}}"#)).await.unwrap();
+        session.outbound_courier.wait_for(|message| {
+            matches!(message, Some(Sequenced { message: OutboundMessage::Processed { id: Some(id) }, .. }) if id == "2")
+        }).await.unwrap();
+        assert_eq!(session.status.borrow().state, StateKind::OrderCreated);
+
+        drop(session.customer);
+        drop(session.courier);
+        let outcome = actor.await.unwrap();
+        assert!(outcome.transitions.iter().all(|transition| transition.to != "OrderInTransit"));
+    }
+
+    #[test]
+    fn queued_chat_is_kept_until_sent() {
+        let (mut actor, _session) = session("chat-test");
+        actor.outbound_customer.queue(OutboundMessage::OrderComplete, Some(1));
+        let queued: Option<QueuedChat> = actor.outbound_customer.chat.front();
+        assert_eq!(queued.map(|queued| queued.relayed), Some(Some(1)));
+        actor.outbound_customer.chat.sent();
+        assert!(actor.outbound_customer.chat.front().is_none());
+    }
+}
@@ -1,4 +1,8 @@
-use std::fmt::{Display, Formatter};
+use crate::handlers::states::StateKind;
+use crate::models::error::UpdateError;
+use crate::models::order_outcome::Leg;
+use crate::models::position::Distance;
+use crate::models::proof::ProofOfDelivery;
 use crate::models::updates::OrderState;
 
 pub enum TypedCommand<S: OrderState> {
@@ -6,9 +10,11 @@ pub enum TypedCommand<S: OrderState> {
     SendCustomerNotify(S::OutboundCustomerUpdate),
     ProcessedCourierUpdate,
     ProcessedCustomerUpdate,
-    CustomerError(String),
-    CourierError(String),
+    CustomerError(UpdateError),
+    CourierError(UpdateError),
     Transition(StateKind),
+    RecordProof(ProofOfDelivery),
+    RecordDistance(Leg, Distance),
     OrderComplete
 }
 
@@ -20,22 +26,7 @@ pub enum Command {
     CustomerError(serde_json::Value),
     CourierError(serde_json::Value),
     Transition(StateKind),
+    RecordProof(ProofOfDelivery),
+    RecordDistance(Leg, Distance),
     OrderComplete
 }
-
-#[derive(Debug)]
-pub enum StateKind {
-    OrderCreated,
-    OrderInTransit,
-    OrderDelivered,
-}
-
-impl Display for StateKind {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            StateKind::OrderCreated => write!(f, "OrderCreated"),
-            StateKind::OrderInTransit => write!(f, "OrderInTransit"),
-            StateKind::OrderDelivered => write!(f, "OrderDelivered"),
-        }
-    }
-}
\ No newline at end of file
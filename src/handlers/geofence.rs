@@ -0,0 +1,140 @@
+use std::env;
+use std::time::{Duration, Instant};
+use crate::models::position::{Distance, Position};
+
+pub static GEOFENCE_CONFIG: once_cell::sync::Lazy<GeofenceConfig> = once_cell::sync::Lazy::new(GeofenceConfig::init);
+
+/// What happens when the courier triggers a geofence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeofenceMode {
+    /// Geofence is not evaluated.
+    Off,
+    /// The courier is prompted to perform the action themselves.
+    Suggest,
+    /// The action is performed on the courier's behalf.
+    Auto,
+}
+
+impl GeofenceMode {
+    fn from_env(name: &str) -> Self {
+        match env::var(name).as_deref() {
+            Ok("off") => GeofenceMode::Off,
+            Ok("auto") => GeofenceMode::Auto,
+            _ => GeofenceMode::Suggest,
+        }
+    }
+}
+
+pub struct GeofenceConfig {
+    pub pickup_mode: GeofenceMode,
+    pub pickup_radius_m: f64,
+    pub dropoff_mode: GeofenceMode,
+    pub dropoff_radius_m: f64,
+    pub dropoff_dwell: Duration,
+}
+
+impl GeofenceConfig {
+    pub fn init() -> Self {
+        let meters = |name: &str, default: f64| env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .unwrap_or(default);
+
+        Self {
+            pickup_mode: GeofenceMode::from_env("PICKUP_GEOFENCE_MODE"),
+            pickup_radius_m: meters("PICKUP_GEOFENCE_RADIUS_M", 50.0),
+            dropoff_mode: GeofenceMode::from_env("DROPOFF_GEOFENCE_MODE"),
+            dropoff_radius_m: meters("DROPOFF_GEOFENCE_RADIUS_M", 50.0),
+            dropoff_dwell: Duration::from_secs(env::var("DROPOFF_DWELL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(30)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GeofenceEvent {
+    Entered,
+    Dwelled,
+}
+
+/// Follows the courier relative to a circular geofence.
+///
+/// Reports `Entered` on the first position inside the fence and `Dwelled` once the courier
+/// has stayed inside for the configured dwell time. Both fire once per visit; leaving the
+/// fence re-arms them.
+pub struct GeofenceTracker {
+    center: Position,
+    radius_m: f64,
+    dwell: Duration,
+    entered_at: Option<Instant>,
+    dwelled: bool,
+}
+
+impl GeofenceTracker {
+    pub fn new(center: Position, radius_m: f64, dwell: Duration) -> Self {
+        Self { center, radius_m, dwell, entered_at: None, dwelled: false }
+    }
+
+    pub fn update(&mut self, pos: &Position, now: Instant) -> Option<GeofenceEvent> {
+        if self.center.distance_to(pos).meters() > self.radius_m {
+            self.entered_at = None;
+            self.dwelled = false;
+            return None;
+        }
+        match self.entered_at {
+            None => {
+                self.entered_at = Some(now);
+                Some(GeofenceEvent::Entered)
+            }
+            Some(at) if !self.dwelled && now.duration_since(at) >= self.dwell => {
+                self.dwelled = true;
+                Some(GeofenceEvent::Dwelled)
+            }
+            Some(_) => None,
+        }
+    }
+
+    pub fn distance_to_center(&self, pos: &Position) -> Distance {
+        self.center.distance_to(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::models::position::Position;
+    use super::{GeofenceEvent, GeofenceTracker};
+
+    const CENTER: Position = Position { lat: 52.5, lon: 13.4 };
+    // About 20 m and 111 m north of the center
+    const INSIDE: Position = Position { lat: 52.50018, lon: 13.4 };
+    const OUTSIDE: Position = Position { lat: 52.501, lon: 13.4 };
+
+    #[test]
+    fn reports_entering_and_dwelling_once_per_visit() {
+        let mut fence = GeofenceTracker::new(CENTER, 50.0, Duration::from_secs(30));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(fence.update(&OUTSIDE, at(0)), None);
+        assert_eq!(fence.update(&INSIDE, at(1)), Some(GeofenceEvent::Entered));
+        assert_eq!(fence.update(&CENTER, at(20)), None);
+        assert_eq!(fence.update(&INSIDE, at(31)), Some(GeofenceEvent::Dwelled));
+        assert_eq!(fence.update(&INSIDE, at(60)), None);
+
+        // Leaving re-arms both events
+        assert_eq!(fence.update(&OUTSIDE, at(61)), None);
+        assert_eq!(fence.update(&INSIDE, at(62)), Some(GeofenceEvent::Entered));
+        assert_eq!(fence.update(&INSIDE, at(92)), Some(GeofenceEvent::Dwelled));
+    }
+
+    #[test]
+    fn dwells_on_the_next_position_without_a_dwell_time() {
+        let mut fence = GeofenceTracker::new(CENTER, 50.0, Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(fence.update(&INSIDE, now), Some(GeofenceEvent::Entered));
+        assert_eq!(fence.update(&INSIDE, now), Some(GeofenceEvent::Dwelled));
+    }
+}
@@ -1,23 +1,59 @@
+use std::fmt::Display;
 use axum::async_trait;
+use serde::de::DeserializeOwned;
 use crate::handlers::events::{Command, TypedCommand};
 use crate::handlers::processor::{UpdateProcessor, WebSocketUpdateProcessor};
-use crate::models::updates::{OrderState, OrderCreated, OrderInTransit, OrderDelivered};
+use crate::handlers::protocol::{Cbor, InboundFrame, MessagePack};
+use crate::handlers::states::{is_courier_update, is_customer_update};
+use crate::models::error::{ErrorWithMessage, UpdateError};
+use crate::models::updates::OrderState;
 
-pub trait UpdateDeserializer<S: OrderState> {
-    fn deserialize_courier_update(&mut self, message: String) -> serde_json::Result<S::InboundCourierUpdate>;
-    fn deserialize_customer_update(&mut self, message: String) -> serde_json::Result<S::InboundCustomerUpdate>;
+/// An encoded inbound message.
+pub trait Decode {
+    type Error: Display;
+
+    fn decode<T: DeserializeOwned>(&self) -> Result<T, Self::Error>;
+}
+
+impl Decode for String {
+    type Error = serde_json::Error;
+
+    fn decode<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(self)
+    }
+}
+
+impl Decode for MessagePack {
+    type Error = rmp_serde::decode::Error;
+
+    fn decode<T: DeserializeOwned>(&self) -> Result<T, Self::Error> {
+        rmp_serde::from_slice(&self.0)
+    }
+}
+
+impl Decode for Cbor {
+    type Error = ErrorWithMessage;
+
+    fn decode<T: DeserializeOwned>(&self) -> Result<T, Self::Error> {
+        // CBOR encoders commonly write whole floats as integers, which ciborium refuses to read
+        // into an f64, so go through a JSON value that converts between them
+        let value: serde_json::Value = ciborium::de::from_reader(self.0.as_slice())
+            .map_err(|e| ErrorWithMessage::new(e.to_string()))?;
+        serde_json::from_value(value).map_err(|e| ErrorWithMessage::new(e.to_string()))
+    }
+}
+
+pub trait UpdateDeserializer<S: OrderState, M> {
+    type Error: Display;
+
+    fn deserialize_courier_update(&mut self, message: &M) -> Result<S::InboundCourierUpdate, Self::Error>;
+    fn deserialize_customer_update(&mut self, message: &M) -> Result<S::InboundCustomerUpdate, Self::Error>;
 }
 
 pub trait UpdateSerializer<S: OrderState> {
     fn serialize_courier_update(&self, update: S::OutboundCourierUpdate) -> serde_json::Value;
     fn serialize_customer_update(&self, update: S::OutboundCustomerUpdate) -> serde_json::Value;
-    fn serialize_error(&self, error: String) -> serde_json::Value;
-}
-
-#[async_trait]
-pub trait UpdateSender<S: OrderState>: Send {
-    async fn outbound_courier_update(&mut self, update: String);
-    async fn outbound_customer_update(&mut self, update: String);
+    fn serialize_error(&self, error: UpdateError) -> serde_json::Value;
 }
 
 #[async_trait]
@@ -26,6 +62,28 @@ pub trait UpdateHandler<M> {
     async fn inbound_customer_update(&mut self, message: M) -> Vec<Command>;
 }
 
+/// Handles the updates of a state in every codec a participant may use.
+#[async_trait]
+pub trait FrameHandler: UpdateHandler<String> + UpdateHandler<MessagePack> + UpdateHandler<Cbor> + Send + Sync {
+    async fn inbound_courier_frame(&mut self, frame: InboundFrame) -> Vec<Command> {
+        match frame {
+            InboundFrame::Json(message) => UpdateHandler::<String>::inbound_courier_update(self, message).await,
+            InboundFrame::MessagePack(message) => UpdateHandler::<MessagePack>::inbound_courier_update(self, message).await,
+            InboundFrame::Cbor(message) => UpdateHandler::<Cbor>::inbound_courier_update(self, message).await,
+        }
+    }
+
+    async fn inbound_customer_frame(&mut self, frame: InboundFrame) -> Vec<Command> {
+        match frame {
+            InboundFrame::Json(message) => UpdateHandler::<String>::inbound_customer_update(self, message).await,
+            InboundFrame::MessagePack(message) => UpdateHandler::<MessagePack>::inbound_customer_update(self, message).await,
+            InboundFrame::Cbor(message) => UpdateHandler::<Cbor>::inbound_customer_update(self, message).await,
+        }
+    }
+}
+
+impl<T> FrameHandler for T where T: UpdateHandler<String> + UpdateHandler<MessagePack> + UpdateHandler<Cbor> + Send + Sync {}
+
 pub struct WebSocketUpdateHandler<S: OrderState> {
     processor: WebSocketUpdateProcessor<S>,
 }
@@ -44,18 +102,22 @@ impl<S: OrderState> WebSocketUpdateHandler<S> {
             TypedCommand::ProcessedCourierUpdate => Command::ProcessedCourierUpdate,
             TypedCommand::CustomerError(e) => Command::CustomerError(self.serialize_error(e)),
             TypedCommand::CourierError(e) => Command::CourierError(self.serialize_error(e)),
+            TypedCommand::RecordProof(proof) => Command::RecordProof(proof),
+            TypedCommand::RecordDistance(leg, distance) => Command::RecordDistance(leg, distance),
             TypedCommand::OrderComplete => Command::OrderComplete,
         }
     }
 }
 
-impl<S: OrderState> UpdateDeserializer<S> for WebSocketUpdateHandler<S> {
-    fn deserialize_courier_update(&mut self, message: String) -> serde_json::Result<S::InboundCourierUpdate> {
-        serde_json::from_str(&message)
+impl<S: OrderState, M: Decode> UpdateDeserializer<S, M> for WebSocketUpdateHandler<S> {
+    type Error = M::Error;
+
+    fn deserialize_courier_update(&mut self, message: &M) -> Result<S::InboundCourierUpdate, Self::Error> {
+        message.decode()
     }
 
-    fn deserialize_customer_update(&mut self, message: String) -> serde_json::Result<S::InboundCustomerUpdate> {
-        serde_json::from_str(&message)
+    fn deserialize_customer_update(&mut self, message: &M) -> Result<S::InboundCustomerUpdate, Self::Error> {
+        message.decode()
     }
 }
 
@@ -68,44 +130,49 @@ impl<S: OrderState> UpdateSerializer<S> for WebSocketUpdateHandler<S> {
         serde_json::to_value(&update).unwrap()
     }
 
-    fn serialize_error(&self, error: String) -> serde_json::Value {
+    fn serialize_error(&self, error: UpdateError) -> serde_json::Value {
         serde_json::to_value(&error).unwrap()
     }
 }
 
-crate::impl_update_handler!(String, OrderCreated);
-crate::impl_update_handler!(String, OrderInTransit);
-crate::impl_update_handler!(String, OrderDelivered);
-
-#[macro_export]
-macro_rules! impl_update_handler {
-    ($msg:ty, $state:ty) => {
-        #[async_trait]
-        impl UpdateHandler<$msg> for WebSocketUpdateHandler<$state> {
-            async fn inbound_courier_update(&mut self, message: String) -> Vec<Command> {
-                match self.deserialize_courier_update(message) {
-                    Ok(update) => self.processor.process_courier_update(update).await
-                        .into_iter()
-                        .map(|command| self.serialize_command(command))
-                        .collect(),
-                    Err(e) => {
-                        vec![Command::CourierError(self.serialize_error(format!("Error deserializing update: {}", e)))]
-                    }
-                }
+#[async_trait]
+impl<S, M> UpdateHandler<M> for WebSocketUpdateHandler<S>
+    where S: OrderState + Send + Sync,
+          S::InboundCourierUpdate: Send,
+          S::InboundCustomerUpdate: Send,
+          WebSocketUpdateProcessor<S>: UpdateProcessor<S>,
+          M: Decode + Send + 'static {
+    async fn inbound_courier_update(&mut self, message: M) -> Vec<Command> {
+        let update = UpdateDeserializer::<S, M>::deserialize_courier_update(self, &message)
+            .map_err(|e| e.to_string());
+        match update {
+            Ok(update) => self.processor.process_courier_update(update).await
+                .into_iter()
+                .map(|command| self.serialize_command(command))
+                .collect(),
+            Err(_) if is_courier_update(&message) => {
+                vec![Command::CourierError(self.serialize_error(UpdateError::not_allowed_in_state(S::state_name())))]
             }
+            Err(e) => {
+                vec![Command::CourierError(self.serialize_error(UpdateError::invalid_message(format!("Error deserializing update: {}", e))))]
+            }
+        }
+    }
 
-            async fn inbound_customer_update(&mut self, message: String) -> Vec<Command> {
-                match self.deserialize_customer_update(message) {
-                    Ok(update) => self.processor.process_customer_update(update).await
-                        .into_iter()
-                        .map(|command| self.serialize_command(command))
-                        .collect(),
-                    Err(e) => {
-                        vec![Command::CustomerError(self.serialize_error(format!("Error deserializing update: {}", e)))]
-                    }
-                }
+    async fn inbound_customer_update(&mut self, message: M) -> Vec<Command> {
+        let update = UpdateDeserializer::<S, M>::deserialize_customer_update(self, &message)
+            .map_err(|e| e.to_string());
+        match update {
+            Ok(update) => self.processor.process_customer_update(update).await
+                .into_iter()
+                .map(|command| self.serialize_command(command))
+                .collect(),
+            Err(_) if is_customer_update(&message) => {
+                vec![Command::CustomerError(self.serialize_error(UpdateError::not_allowed_in_state(S::state_name())))]
+            }
+            Err(e) => {
+                vec![Command::CustomerError(self.serialize_error(UpdateError::invalid_message(format!("Error deserializing update: {}", e))))]
             }
         }
-    };
-    () => {};
-}
\ No newline at end of file
+    }
+}
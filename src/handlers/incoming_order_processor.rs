@@ -6,15 +6,16 @@ use rdkafka::config::RDKafkaLogLevel;
 use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::message::BorrowedMessage;
 use rdkafka::producer::{FutureProducer, FutureRecord};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use tokio::sync::{oneshot, Semaphore, SemaphorePermit, TryAcquireError};
-use serde::de::Error;
-use serde_json::json;
-use tokio::sync::oneshot::error::RecvError;
-use tokio::sync::oneshot::Receiver;
 use tracing::{error, info};
+use crate::models::chat::ChatTranscript;
 use crate::models::error::ErrorWithMessage;
+use crate::models::order_info::{OrderInfo, Participants};
+use crate::models::order_outcome::{unix_millis, OrderCompletion, OrderOutcome};
 
+use super::timeline_store::TimelineStore;
+use super::track_store::TrackStore;
 use super::websocket_actor::OrderSessionHandler;
 
 pub static HANDLERS: once_cell::sync::Lazy<dashmap::DashMap<String, OrderSessionHandler>> = once_cell::sync::Lazy::new(DashMap::new);
@@ -25,6 +26,18 @@ pub static SEMAPHORE: once_cell::sync::Lazy<Semaphore> = once_cell::sync::Lazy::
         .unwrap_or(10_000)));
 pub static HOST: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(|| env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()));
 pub static PORT: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(|| env::var("PORT").unwrap_or_else(|_| "3000".to_string()));
+/// Names this instance in the consumer groups it reads a whole topic with: `HOSTNAME`, or a random
+/// name if that isn't set, so that instances never share a group and split the topic between them.
+pub static INSTANCE_ID: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(|| env::var("HOSTNAME").ok()
+    .filter(|hostname| !hostname.is_empty())
+    .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())));
+
+/// Most bytes of events published to `order_timelines` in one message, below the broker's 1 MB
+/// `message.max.bytes` to leave room for the rest of the message.
+static TIMELINE_PART_MAX_BYTES: once_cell::sync::Lazy<usize> = once_cell::sync::Lazy::new(|| env::var("TIMELINE_PART_MAX_BYTES")
+    .ok()
+    .and_then(|s| s.parse::<usize>().ok())
+    .unwrap_or(900_000));
 
 pub struct IncomingOrderProcessor;
 
@@ -84,8 +97,6 @@ impl IncomingOrderProcessor {
         if let Ok(order_info) = serde_json::from_str::<OrderInfo>(
             msg.payload_view::<str>().ok_or(ErrorWithMessage::new("Message read failure".to_string()))??) {
             let order_id = order_info.order_id.clone();
-            let customer_id = order_info.customer_id.clone();
-            let courier_id = order_info.courier_id.clone();
 
             let links = GeolocationLinks {
                 order_id: order_id.clone(),
@@ -94,17 +105,18 @@ impl IncomingOrderProcessor {
             };
 
 
-            let (handle, completed) = oneshot::channel::<()>();
+            let (handle, completed) = oneshot::channel::<OrderOutcome>();
 
             let order_id_clone = order_id.clone();
             let producer_clone = producer.clone();
+            // Reported when the session stops without an outcome of its own
+            let aborted = OrderOutcome::new(&order_info, unix_millis());
             tokio::spawn(async move {
-                completed.await.ok();
-                Self::on_order_finish(acq, order_id_clone, producer_clone).await;
+                let outcome = completed.await.unwrap_or(aborted);
+                Self::on_order_finish(acq, order_id_clone, outcome, producer_clone).await;
             });
 
-            let session_handler = OrderSessionHandler::new
-                (order_id.clone(), customer_id.clone(), courier_id.clone(), handle);
+            let session_handler = OrderSessionHandler::new(order_info, handle);
             HANDLERS.insert(order_id.clone(), session_handler);
 
             producer.send(FutureRecord::to("geolocation_info")
@@ -117,29 +129,41 @@ impl IncomingOrderProcessor {
         Ok(())
     }
 
-    async fn on_order_finish(acq: SemaphorePermit<'_>, order_id: String, producer: FutureProducer) {
+    async fn on_order_finish(acq: SemaphorePermit<'_>, order_id: String, outcome: OrderOutcome, producer: FutureProducer) {
         std::mem::drop(acq);
+        let participants = Participants { customer_id: outcome.customer_id.clone(), courier_id: outcome.courier_id.clone() };
+        TrackStore::persist(&order_id, participants.clone()).await;
+        let timeline = TimelineStore::persist(&order_id, participants).await;
+        // Removed last, the session answers who may read the order until its track and timeline are persisted
         HANDLERS.remove(&order_id);
 
-        let status = json!({
-                    "order_id": order_id,
-                    "status": "Delivered"
-                });
-
+        let completion = OrderCompletion::new(&outcome, unix_millis());
         producer.send(FutureRecord::to("processed_orders")
-                          .payload(serde_json::to_string(&status).unwrap().as_bytes())
+                          .payload(serde_json::to_string(&completion).unwrap().as_bytes())
                           .key(&order_id), Duration::from_secs(0)).await
             .map_err(|(e, _)| ErrorWithMessage::new
                 (format!("Kafka send error {}", e)))
             .map_or_else(|e| error!("{}", e), |_| {});
-    }
-}
 
-#[derive(Deserialize)]
-struct OrderInfo {
-    order_id: String,
-    customer_id: String,
-    courier_id: String,
+        for part in timeline.iter().flat_map(|timeline| timeline.parts(*TIMELINE_PART_MAX_BYTES)) {
+            producer.send(FutureRecord::to("order_timelines")
+                              .payload(serde_json::to_string(&part).unwrap().as_bytes())
+                              .key(&order_id), Duration::from_secs(0)).await
+                .map_err(|(e, _)| ErrorWithMessage::new
+                    (format!("Kafka send error {}", e)))
+                .map_or_else(|e| error!("{}", e), |_| {});
+        }
+
+        if !outcome.chat.is_empty() {
+            let transcript = ChatTranscript { order_id: &order_id, messages: &outcome.chat };
+            producer.send(FutureRecord::to("order_chat_transcripts")
+                              .payload(serde_json::to_string(&transcript).unwrap().as_bytes())
+                              .key(&order_id), Duration::from_secs(0)).await
+                .map_err(|(e, _)| ErrorWithMessage::new
+                    (format!("Kafka send error {}", e)))
+                .map_or_else(|e| error!("{}", e), |_| {});
+        }
+    }
 }
 
 #[derive(Serialize)]
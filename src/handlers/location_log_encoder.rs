@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+use apache_avro::Schema;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+use crate::models::error::ErrorWithMessage;
+use crate::models::location_log::{LocationLog, LocationLogBatch, LocationLogEnvelope};
+
+/// Version of the schemas in `schemas/`, sent in the `schema-version` header of every record.
+pub const LOCATION_LOG_SCHEMA_VERSION: u32 = 1;
+const SCHEMA_NAMESPACE: &str = "foodio.geolocation";
+const MAX_REGISTRATION_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A location log payload with a versioned schema in `schemas/`.
+pub trait LocationLogSchema: Serialize {
+    const NAME: &'static str;
+    const AVRO_SCHEMA: &'static str;
+    type Proto: Message;
+
+    fn to_proto(&self) -> Self::Proto;
+}
+
+impl LocationLogSchema for LocationLog {
+    const NAME: &'static str = "LocationLog";
+    const AVRO_SCHEMA: &'static str = include_str!("../../schemas/location_log.avsc");
+    type Proto = proto::LocationLog;
+
+    fn to_proto(&self) -> Self::Proto {
+        proto::LocationLog { lat: self.lat, lon: self.lon }
+    }
+}
+
+impl LocationLogSchema for LocationLogEnvelope {
+    const NAME: &'static str = "LocationLogEnvelope";
+    const AVRO_SCHEMA: &'static str = include_str!("../../schemas/location_log_envelope.avsc");
+    type Proto = proto::LocationLogEnvelope;
+
+    fn to_proto(&self) -> Self::Proto {
+        proto::LocationLogEnvelope {
+            order_id: self.order_id.clone(),
+            courier_id: self.courier_id.clone(),
+            timestamp: self.timestamp,
+            lat: self.lat,
+            lon: self.lon,
+        }
+    }
+}
+
+impl LocationLogSchema for LocationLogBatch {
+    const NAME: &'static str = "LocationLogBatch";
+    const AVRO_SCHEMA: &'static str = include_str!("../../schemas/location_log_batch.avsc");
+    type Proto = proto::LocationLogBatch;
+
+    fn to_proto(&self) -> Self::Proto {
+        proto::LocationLogBatch {
+            order_id: self.order_id.clone(),
+            courier_id: self.courier_id.clone(),
+            points: self.points.iter()
+                .map(|p| proto::LocationLogPoint { timestamp: p.timestamp, lat: p.lat, lon: p.lon })
+                .collect(),
+        }
+    }
+}
+
+/// Protobuf messages matching `schemas/location_log.proto`.
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocationLog {
+        #[prost(double, tag = "1")]
+        pub lat: f64,
+        #[prost(double, tag = "2")]
+        pub lon: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocationLogEnvelope {
+        #[prost(string, tag = "1")]
+        pub order_id: String,
+        #[prost(string, tag = "2")]
+        pub courier_id: String,
+        #[prost(uint64, tag = "3")]
+        pub timestamp: u64,
+        #[prost(double, tag = "4")]
+        pub lat: f64,
+        #[prost(double, tag = "5")]
+        pub lon: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocationLogPoint {
+        #[prost(uint64, tag = "1")]
+        pub timestamp: u64,
+        #[prost(double, tag = "2")]
+        pub lat: f64,
+        #[prost(double, tag = "3")]
+        pub lon: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocationLogBatch {
+        #[prost(string, tag = "1")]
+        pub order_id: String,
+        #[prost(string, tag = "2")]
+        pub courier_id: String,
+        #[prost(message, repeated, tag = "3")]
+        pub points: Vec<LocationLogPoint>,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LocationLogFormat {
+    MessagePack,
+    Json,
+    Protobuf,
+    Avro,
+}
+
+impl LocationLogFormat {
+    pub fn init() -> Self {
+        match env::var("LOCATION_LOG_FORMAT").as_deref() {
+            Ok("json") => LocationLogFormat::Json,
+            Ok("protobuf") => LocationLogFormat::Protobuf,
+            Ok("avro") => LocationLogFormat::Avro,
+            _ => LocationLogFormat::MessagePack,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            LocationLogFormat::MessagePack => "application/msgpack",
+            LocationLogFormat::Json => "application/json",
+            LocationLogFormat::Protobuf => "application/x-protobuf",
+            LocationLogFormat::Avro => "application/vnd.apache.avro+binary",
+        }
+    }
+}
+
+struct AvroSchema {
+    schema: Schema,
+    /// Id assigned by the schema registry. Records are framed in the Confluent wire format when set.
+    registry_id: Option<u32>,
+}
+
+const AVRO_SCHEMAS: [(&str, &str); 3] = [
+    (LocationLog::NAME, LocationLog::AVRO_SCHEMA),
+    (LocationLogEnvelope::NAME, LocationLogEnvelope::AVRO_SCHEMA),
+    (LocationLogBatch::NAME, LocationLogBatch::AVRO_SCHEMA),
+];
+
+/// Encodes location log payloads in the format selected by `LOCATION_LOG_FORMAT`.
+///
+/// For Avro, the schemas are registered under their fully qualified record name in the
+/// schema registry at `SCHEMA_REGISTRY_URL`, if one is configured. Until the registry has
+/// answered, payloads are encoded without the registry framing, see `awaiting_schema_id`.
+pub struct LocationLogEncoder {
+    format: LocationLogFormat,
+    avro: HashMap<&'static str, AvroSchema>,
+    registry: Option<String>,
+}
+
+impl LocationLogEncoder {
+    pub fn init() -> Self {
+        let format = LocationLogFormat::init();
+        let mut avro = HashMap::new();
+        let mut registry = None;
+        if format == LocationLogFormat::Avro {
+            registry = env::var("SCHEMA_REGISTRY_URL").ok();
+            for (name, definition) in AVRO_SCHEMAS {
+                let schema = Schema::parse_str(definition).expect("Invalid Avro schema");
+                avro.insert(name, AvroSchema { schema, registry_id: None });
+            }
+        }
+        Self { format, avro, registry }
+    }
+
+    /// The schema registry, while the schemas still have to be registered there.
+    pub fn pending_registry(&self) -> Option<&str> {
+        self.registry.as_deref()
+            .filter(|_| self.avro.values().any(|avro| avro.registry_id.is_none()))
+    }
+
+    /// Registers the Avro schemas at `registry`, retrying with backoff until it accepts all of them.
+    pub async fn register_schemas(registry: String) -> HashMap<&'static str, u32> {
+        let mut ids = HashMap::new();
+        for (name, definition) in AVRO_SCHEMAS {
+            let mut backoff = Duration::from_secs(1);
+            loop {
+                match Self::register_schema(&registry, name, definition).await {
+                    Ok(id) => {
+                        ids.insert(name, id);
+                        break;
+                    }
+                    Err(e) => warn!("Failed to register schema {}, retrying in {:?}: {}", name, backoff, e),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_REGISTRATION_BACKOFF);
+            }
+        }
+        ids
+    }
+
+    pub fn set_registry_ids(&mut self, ids: HashMap<&'static str, u32>) {
+        for (name, id) in ids {
+            if let Some(avro) = self.avro.get_mut(name) {
+                avro.registry_id = Some(id);
+            }
+        }
+    }
+
+    /// Name of the schema whose registry id a payload of `T` is encoded without, if any.
+    pub fn awaiting_schema_id<T: LocationLogSchema>(&self) -> Option<String> {
+        self.avro.get(T::NAME)
+            .filter(|avro| self.registry.is_some() && avro.registry_id.is_none())
+            .map(|_| T::NAME.to_string())
+    }
+
+    pub fn registry_id(&self, name: &str) -> Option<u32> {
+        self.avro.get(name).and_then(|avro| avro.registry_id)
+    }
+
+    /// Frames an Avro datum in the Confluent wire format.
+    pub fn frame(id: u32, datum: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(5 + datum.len());
+        framed.push(0);
+        framed.extend_from_slice(&id.to_be_bytes());
+        framed.extend_from_slice(datum);
+        framed
+    }
+
+    async fn register_schema(registry: &str, name: &str, definition: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+        #[derive(Deserialize)]
+        struct Registered {
+            id: u32,
+        }
+
+        let subject = format!("{}.{}", SCHEMA_NAMESPACE, name);
+        let request = hyper::Request::post(format!("{}/subjects/{}/versions", registry.trim_end_matches('/'), subject))
+            .header(hyper::header::CONTENT_TYPE, "application/vnd.schemaregistry.v1+json")
+            .body(hyper::Body::from(serde_json::to_vec(&json!({ "schema": definition }))?))?;
+        let response = hyper::Client::new().request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(ErrorWithMessage::new(format!("Schema registry returned {} for {}: {}",
+                                                     status, subject, String::from_utf8_lossy(&body))).into());
+        }
+
+        let id = serde_json::from_slice::<Registered>(&body)?.id;
+        info!("Registered schema {} with id {}", subject, id);
+        Ok(id)
+    }
+
+    pub fn encode<T: LocationLogSchema>(&self, value: &T) -> Vec<u8> {
+        match self.format {
+            LocationLogFormat::MessagePack => rmp_serde::to_vec(value).unwrap(),
+            LocationLogFormat::Json => serde_json::to_vec(value).unwrap(),
+            LocationLogFormat::Protobuf => value.to_proto().encode_to_vec(),
+            LocationLogFormat::Avro => {
+                let avro = &self.avro[T::NAME];
+                let datum = apache_avro::to_avro_datum(&avro.schema, apache_avro::to_value(value).unwrap())
+                    .expect("Location log does not match its Avro schema");
+                match avro.registry_id {
+                    Some(id) => Self::frame(id, &datum),
+                    None => datum,
+                }
+            }
+        }
+    }
+
+    /// Headers identifying the encoding and schema of a record carrying `T`.
+    pub fn headers<T: LocationLogSchema>(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("content-type".to_string(), self.format.content_type().to_string()),
+            ("schema".to_string(), format!("{}.{}", SCHEMA_NAMESPACE, T::NAME)),
+            ("schema-version".to_string(), LOCATION_LOG_SCHEMA_VERSION.to_string()),
+        ];
+        if let Some(id) = self.avro.get(T::NAME).and_then(|avro| avro.registry_id) {
+            headers.push(("schema-id".to_string(), id.to_string()));
+        }
+        headers
+    }
+}
@@ -1,18 +1,395 @@
+use std::collections::HashMap;
 use std::env;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use std::time::Duration;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
 use rdkafka::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use tokio::sync::mpsc;
-use crate::models::location_log::LocationLog;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use crate::models::location_log::{LocationLog, LocationLogBatch, LocationLogEnvelope};
+use super::location_log_encoder::LocationLogEncoder;
+use super::location_spool::{LocationSpool, SpoolConfig, SPOOL_METRICS};
 
-pub static LOCATION_LOGGER: once_cell::sync::OnceCell<mpsc::Sender<(Arc<String>, LocationLog)>> = once_cell::sync::OnceCell::new();
+pub static LOCATION_LOGGER: once_cell::sync::OnceCell<mpsc::Sender<LocationLogEnvelope>> = once_cell::sync::OnceCell::new();
+
+/// Where location logs are produced to.
+pub enum LocationLogTopic {
+    /// Default layout: one `order.{order_id}.location_log` topic per order, carrying bare `LocationLog`s.
+    /// Points are never batched in this layout.
+    PerOrder,
+    /// All orders share one topic, keyed by order id, carrying a `LocationLogEnvelope` per point,
+    /// or `LocationLogBatch`es when batching is on.
+    /// Opted into with `LOCATION_LOG_TOPIC_MODE=shared`.
+    Shared(String),
+}
+
+impl LocationLogTopic {
+    pub fn init() -> Self {
+        match env::var("LOCATION_LOG_TOPIC_MODE").as_deref() {
+            Ok("shared") => LocationLogTopic::Shared(env::var("LOCATION_LOG_TOPIC")
+                .unwrap_or_else(|_| "order_location_log".to_string())),
+            Ok("per_order") | Err(_) => LocationLogTopic::PerOrder,
+            Ok(mode) => {
+                warn!("Unknown LOCATION_LOG_TOPIC_MODE {}, using per_order", mode);
+                LocationLogTopic::PerOrder
+            }
+        }
+    }
+
+    fn single_record(&self, encoder: &LocationLogEncoder, envelope: LocationLogEnvelope) -> PendingRecord {
+        match self {
+            LocationLogTopic::PerOrder => {
+                let log = envelope.log();
+                PendingRecord {
+                    topic: format!("order.{}.location_log", envelope.order_id),
+                    key: None,
+                    payload: encoder.encode(&log),
+                    headers: encoder.headers::<LocationLog>(),
+                    awaiting_schema_id: encoder.awaiting_schema_id::<LocationLog>(),
+                    attempt: 0,
+                }
+            }
+            LocationLogTopic::Shared(topic) => PendingRecord {
+                topic: topic.clone(),
+                payload: encoder.encode(&envelope),
+                headers: encoder.headers::<LocationLogEnvelope>(),
+                awaiting_schema_id: encoder.awaiting_schema_id::<LocationLogEnvelope>(),
+                key: Some(envelope.order_id),
+                attempt: 0,
+            },
+        }
+    }
+
+    fn batch_record(&self, encoder: &LocationLogEncoder, batch: LocationLogBatch) -> PendingRecord {
+        let topic = match self {
+            LocationLogTopic::Shared(topic) => topic.clone(),
+            LocationLogTopic::PerOrder => unreachable!("Batches are only published to the shared topic"),
+        };
+        PendingRecord {
+            topic,
+            payload: encoder.encode(&batch),
+            headers: encoder.headers::<LocationLogBatch>(),
+            awaiting_schema_id: encoder.awaiting_schema_id::<LocationLogBatch>(),
+            key: Some(batch.order_id),
+            attempt: 0,
+        }
+    }
+}
+
+pub struct LocationLoggerConfig {
+    pub topic: LocationLogTopic,
+    /// How long points of an order are collected before being published together.
+    /// Zero publishes every point on its own.
+    pub batch_interval: Duration,
+    pub batch_max_points: usize,
+    pub compression: String,
+    pub linger_ms: String,
+    pub max_in_flight: usize,
+    pub max_retries: u32,
+    pub spool_replay_interval: Duration,
+}
+
+impl LocationLoggerConfig {
+    pub fn init() -> Self {
+        let number = |name: &str, default: u64| env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(default);
+
+        Self {
+            topic: LocationLogTopic::init(),
+            batch_interval: Duration::from_millis(number("LOCATION_LOG_BATCH_MS", 0)),
+            batch_max_points: number("LOCATION_LOG_BATCH_MAX_POINTS", 100) as usize,
+            compression: env::var("LOCATION_LOG_COMPRESSION").unwrap_or_else(|_| "lz4".to_string()),
+            linger_ms: number("LOCATION_LOG_LINGER_MS", 5).to_string(),
+            max_in_flight: number("LOCATION_LOG_MAX_IN_FLIGHT", 1_000) as usize,
+            max_retries: number("LOCATION_LOG_MAX_RETRIES", 3) as u32,
+            spool_replay_interval: Duration::from_secs(number("LOCATION_LOG_SPOOL_REPLAY_SECS", 5).max(1)),
+        }
+    }
+}
+
+/// Record waiting to be produced, owned so it can be retried or spooled.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PendingRecord {
+    topic: String,
+    key: Option<String>,
+    payload: Vec<u8>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    /// Avro schema whose registry id the payload still has to be framed with
+    #[serde(default)]
+    awaiting_schema_id: Option<String>,
+    #[serde(skip)]
+    attempt: u32,
+}
+
+impl PendingRecord {
+    /// Frames the payload with the registry id it was encoded without, returning whether the
+    /// record can be published.
+    fn complete_schema_id(&mut self, encoder: &LocationLogEncoder) -> bool {
+        let Some(name) = self.awaiting_schema_id.as_deref() else {
+            return true;
+        };
+        let Some(id) = encoder.registry_id(name) else {
+            return false;
+        };
+        self.payload = LocationLogEncoder::frame(id, &self.payload);
+        self.headers.push(("schema-id".to_string(), id.to_string()));
+        self.awaiting_schema_id = None;
+        true
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new(topic: &str, payload: Vec<u8>) -> Self {
+        Self { topic: topic.to_string(), key: None, payload, headers: Vec::new(), awaiting_schema_id: None, attempt: 0 }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn to_record(&self) -> FutureRecord<'_, str, [u8]> {
+        let headers = self.headers.iter()
+            .fold(OwnedHeaders::new_with_capacity(self.headers.len()), |headers, (key, value)| headers.insert(Header {
+                key: key.as_str(),
+                value: Some(value.as_str()),
+            }));
+        let record = FutureRecord::to(self.topic.as_str())
+            .payload(self.payload.as_slice())
+            .headers(headers);
+        match &self.key {
+            Some(key) => record.key(key.as_str()),
+            None => record,
+        }
+    }
+}
+
+type Delivery = (PendingRecord, Result<(), KafkaError>);
+
+/// Outcome of replaying a chunk of the spool: bytes and records that were delivered,
+/// and whether the whole chunk was.
+type Replayed = (u64, u64, bool);
+
+const REPLAY_CHUNK_RECORDS: usize = 500;
+
+/// Keeps up to `max_in_flight` records awaiting acknowledgement and retries failed ones.
+///
+/// Records are enqueued into the producer synchronously, in the order they are published,
+/// so the per-key ordering is only lost for records that had to be retried.
+///
+/// Once a record runs out of retries the broker is considered unavailable: it and every record
+/// published after it go to the spool until the spool has been replayed. Records encoded while
+/// the schema registry was unreachable are spooled the same way and replayed once it answers.
+struct Publisher {
+    producer: FutureProducer,
+    in_flight: FuturesUnordered<BoxFuture<'static, Delivery>>,
+    max_in_flight: usize,
+    max_retries: u32,
+    spool: Option<Arc<Mutex<LocationSpool>>>,
+    spooling: bool,
+    replay: FuturesUnordered<BoxFuture<'static, Replayed>>,
+}
+
+impl Publisher {
+    async fn publish(&mut self, record: PendingRecord) {
+        if self.spooling || record.awaiting_schema_id.is_some() {
+            self.spool_record(record).await;
+            return;
+        }
+
+        while self.in_flight.len() >= self.max_in_flight {
+            self.complete_next().await;
+        }
+
+        loop {
+            let enqueued = self.producer.send_result(record.to_record()).map_err(|(e, _)| e);
+            match enqueued {
+                Ok(delivery) => {
+                    self.in_flight.push(async move {
+                        let result = match delivery.await {
+                            Ok(Ok(_)) => Ok(()),
+                            Ok(Err((e, _))) => Err(e),
+                            Err(_) => Err(KafkaError::Canceled),
+                        };
+                        (record, result)
+                    }.boxed());
+                    return;
+                }
+                Err(KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull)) => {
+                    if !self.complete_next().await {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+                Err(e) => {
+                    self.on_delivery((record, Err(e))).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Waits for the next in-flight record to be acknowledged. Returns false if nothing is in flight.
+    async fn complete_next(&mut self) -> bool {
+        match self.in_flight.next().await {
+            Some(delivery) => {
+                self.on_delivery(delivery).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn on_delivery(&mut self, (mut record, result): Delivery) {
+        let Err(e) = result else {
+            return;
+        };
+        if record.attempt >= self.max_retries {
+            if !self.spooling {
+                error!("Failed to publish location log to {} after {} attempts, spooling: {}", record.topic, record.attempt + 1, e);
+            }
+            self.spool_record(record).await;
+            return;
+        }
+
+        record.attempt += 1;
+        let backoff = Duration::from_millis(100 << record.attempt.min(6));
+        warn!("Failed to publish location log to {}, retrying in {:?}: {}", record.topic, backoff, e);
+        let producer = self.producer.clone();
+        self.in_flight.push(async move {
+            tokio::time::sleep(backoff).await;
+            let result = producer.send(record.to_record(), Duration::from_secs(0)).await
+                .map(|_| ())
+                .map_err(|(e, _)| e);
+            (record, result)
+        }.boxed());
+    }
+
+    async fn spool_record(&mut self, record: PendingRecord) {
+        let Some(spool) = self.spool.as_ref() else {
+            error!("Dropping location log for {}: spool unavailable", record.topic);
+            return;
+        };
+        self.spooling = true;
+        let (record, result) = Self::with_spool(spool, move |spool| {
+            let result = spool.append(&record);
+            (record, result)
+        }).await;
+        if let Err(e) = result {
+            SPOOL_METRICS.dropped_records.fetch_add(1, Ordering::Relaxed);
+            error!("Dropping location log for {}: failed to spool: {}", record.topic, e);
+        }
+    }
+
+    /// Runs `f` on the spool on the blocking thread pool, keeping its file IO off the runtime's workers.
+    async fn with_spool<T: Send + 'static>(spool: &Arc<Mutex<LocationSpool>>,
+                                           f: impl FnOnce(&mut LocationSpool) -> T + Send + 'static) -> T {
+        let spool = spool.clone();
+        tokio::task::spawn_blocking(move || f(&mut spool.lock().unwrap())).await.unwrap()
+    }
+
+    /// Starts replaying the next chunk of the spool, unless a replay is already running or the
+    /// schemas of spooled records aren't registered yet.
+    async fn start_replay(&mut self, encoder: &LocationLogEncoder) {
+        if !self.spooling || !self.replay.is_empty() || encoder.pending_registry().is_some() {
+            return;
+        }
+        let Some(spool) = self.spool.as_ref() else {
+            return;
+        };
+        let chunk = match Self::with_spool(spool, |spool| spool.read_chunk(REPLAY_CHUNK_RECORDS)).await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                error!("Failed to read location log spool, retrying: {}", e);
+                return;
+            }
+        };
+        if chunk.is_empty() {
+            self.spooling = false;
+            return;
+        }
+
+        // A record whose schema the registry doesn't know can never be framed, it is skipped over
+        let chunk: Vec<_> = chunk.into_iter()
+            .map(|(mut record, size)| {
+                if record.complete_schema_id(encoder) {
+                    return (Some(record), size);
+                }
+                error!("Dropping spooled location log for {}: schema {} isn't registered",
+                    record.topic, record.awaiting_schema_id.as_deref().unwrap_or_default());
+                (None, size)
+            })
+            .collect();
+
+        let producer = self.producer.clone();
+        self.replay.push(async move {
+            let mut deliveries = Vec::with_capacity(chunk.len());
+            for (record, size) in &chunk {
+                let delivery = match record {
+                    Some(record) => match producer.send_result(record.to_record()) {
+                        Ok(delivery) => Some(delivery),
+                        Err(_) => break,
+                    },
+                    None => None,
+                };
+                deliveries.push((delivery, *size));
+            }
+
+            let complete = deliveries.len() == chunk.len();
+            let (mut bytes, mut records) = (0, 0);
+            for (delivery, size) in deliveries {
+                match delivery {
+                    Some(delivery) => {
+                        if !matches!(delivery.await, Ok(Ok(_))) {
+                            return (bytes, records, false);
+                        }
+                        records += 1;
+                    }
+                    None => {
+                        SPOOL_METRICS.dropped_records.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                bytes += size;
+            }
+            (bytes, records, complete)
+        }.boxed());
+    }
+
+    async fn on_replayed(&mut self, (bytes, records, complete): Replayed, encoder: &LocationLogEncoder) {
+        let Some(spool) = self.spool.as_ref() else {
+            return;
+        };
+        let (advanced, empty) = Self::with_spool(spool, move |spool| (spool.advance(bytes, records), spool.is_empty())).await;
+        if let Err(e) = advanced {
+            error!("Failed to truncate location log spool: {}", e);
+        }
+        if empty {
+            info!("Location log spool replayed, publishing directly again");
+            self.spooling = false;
+        } else if complete {
+            self.start_replay(encoder).await;
+        }
+    }
+
+    async fn flush(&mut self) {
+        while self.complete_next().await {}
+    }
+}
 
 pub struct LocationLogger;
 
 impl LocationLogger {
     pub async fn run_actor() {
         let broker = env::var("REDPANDA_BROKER").unwrap_or_else(|_| "localhost:19092".to_string());
+        let config = LocationLoggerConfig::init();
         let (tx, mut rx) = mpsc::channel(100_000);
         LOCATION_LOGGER.set(tx).unwrap();
 
@@ -21,17 +398,92 @@ impl LocationLogger {
         let producer: FutureProducer = ClientConfig::new()
             .set("bootstrap.servers", broker.as_str())
             .set("message.timeout.ms", "5000")
+            .set("compression.type", config.compression.as_str())
+            .set("linger.ms", config.linger_ms.as_str())
+            .set("enable.idempotence", "true")
             .create()
             .expect("Producer creation error");
+        let mut encoder = LocationLogEncoder::init();
+        let (registered_tx, mut registered) = mpsc::channel(1);
+        if let Some(registry) = encoder.pending_registry() {
+            let registry = registry.to_string();
+            tokio::spawn(async move {
+                registered_tx.send(LocationLogEncoder::register_schemas(registry).await).await.ok();
+            });
+        }
+
+        let spool = tokio::task::spawn_blocking(|| LocationSpool::open(&SpoolConfig::init())).await.unwrap()
+            .map_err(|e| error!("Location log spool unavailable, failed logs will be dropped: {}", e))
+            .ok();
+        let mut publisher = Publisher {
+            producer,
+            in_flight: FuturesUnordered::new(),
+            max_in_flight: config.max_in_flight.max(1),
+            max_retries: config.max_retries,
+            // Leftovers from a previous run are replayed before anything new is published
+            spooling: spool.as_ref().is_some_and(|spool| !spool.is_empty()),
+            spool: spool.map(|spool| Arc::new(Mutex::new(spool))),
+            replay: FuturesUnordered::new(),
+        };
+        let mut replay_spool = tokio::time::interval(config.spool_replay_interval);
+        let batching = !config.batch_interval.is_zero() && matches!(config.topic, LocationLogTopic::Shared(_));
+        let mut batches: HashMap<String, LocationLogBatch> = HashMap::new();
+        let mut flush_batches = tokio::time::interval(config.batch_interval.max(Duration::from_millis(1)));
 
+        loop {
+            tokio::select! {
+                envelope = rx.recv() => {
+                    let Some(envelope) = envelope else {
+                        break;
+                    };
+                    if !batching {
+                        publisher.publish(config.topic.single_record(&encoder, envelope)).await;
+                        continue;
+                    }
+
+                    let order_id = envelope.order_id.clone();
+                    let full = match batches.get_mut(&order_id) {
+                        Some(batch) if batch.courier_id == envelope.courier_id => {
+                            batch.push(envelope);
+                            batch.points.len() >= config.batch_max_points
+                        }
+                        // A batch belongs to a single courier, so a reassigned order starts a new one
+                        _ => {
+                            if let Some(previous) = batches.insert(order_id.clone(), LocationLogBatch::new(envelope)) {
+                                publisher.publish(config.topic.batch_record(&encoder, previous)).await;
+                            }
+                            false
+                        }
+                    };
+                    if full {
+                        let batch = batches.remove(&order_id).unwrap();
+                        publisher.publish(config.topic.batch_record(&encoder, batch)).await;
+                    }
+                }
+                _ = flush_batches.tick(), if batching => {
+                    for (_, batch) in batches.drain() {
+                        publisher.publish(config.topic.batch_record(&encoder, batch)).await;
+                    }
+                }
+                Some(delivery) = publisher.in_flight.next(), if !publisher.in_flight.is_empty() => {
+                    publisher.on_delivery(delivery).await;
+                }
+                Some(ids) = registered.recv(), if encoder.pending_registry().is_some() => {
+                    encoder.set_registry_ids(ids);
+                    publisher.start_replay(&encoder).await;
+                }
+                _ = replay_spool.tick(), if publisher.spooling => {
+                    publisher.start_replay(&encoder).await;
+                }
+                Some(replayed) = publisher.replay.next(), if !publisher.replay.is_empty() => {
+                    publisher.on_replayed(replayed, &encoder).await;
+                }
+            }
+        }
 
-        while let Some((order_id, location_log)) = rx.recv().await {
-            let payload = rmp_serde::to_vec(&location_log).unwrap();
-            producer.send(
-                FutureRecord::<String, _>::to
-                    (format!("order.{}.location_log", order_id).as_str())
-                    .payload(payload.as_slice()), Duration::from_secs(0)).await
-                .expect("Failed to enqueue");
+        for (_, batch) in batches.drain() {
+            publisher.publish(config.topic.batch_record(&encoder, batch)).await;
         }
+        publisher.flush().await;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,303 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{error, warn};
+use super::location_logger::PendingRecord;
+
+pub static SPOOL_METRICS: SpoolMetrics = SpoolMetrics {
+    spool_bytes: AtomicU64::new(0),
+    spooled_records: AtomicU64::new(0),
+    replayed_records: AtomicU64::new(0),
+    dropped_records: AtomicU64::new(0),
+};
+
+pub struct SpoolMetrics {
+    pub spool_bytes: AtomicU64,
+    pub spooled_records: AtomicU64,
+    pub replayed_records: AtomicU64,
+    pub dropped_records: AtomicU64,
+}
+
+impl SpoolMetrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        format!(
+            "# TYPE location_log_spool_bytes gauge\n\
+             location_log_spool_bytes {}\n\
+             # TYPE location_log_spooled_total counter\n\
+             location_log_spooled_total {}\n\
+             # TYPE location_log_replayed_total counter\n\
+             location_log_replayed_total {}\n\
+             # TYPE location_log_spool_dropped_total counter\n\
+             location_log_spool_dropped_total {}\n",
+            self.spool_bytes.load(Ordering::Relaxed),
+            self.spooled_records.load(Ordering::Relaxed),
+            self.replayed_records.load(Ordering::Relaxed),
+            self.dropped_records.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub struct SpoolConfig {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl SpoolConfig {
+    pub fn init() -> Self {
+        Self {
+            path: env::var("LOCATION_LOG_SPOOL_PATH")
+                .unwrap_or_else(|_| "spool/location_log.spool".to_string())
+                .into(),
+            max_bytes: env::var("LOCATION_LOG_SPOOL_MAX_BYTES")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(256 * 1024 * 1024),
+        }
+    }
+}
+
+/// Append-only file of location log records that could not be published.
+///
+/// Each entry is a little-endian `u32` length followed by the MessagePack-encoded record.
+/// Records are replayed from the front in the order they were appended, and the file is
+/// truncated once everything in it has been replayed.
+///
+/// An entry left incomplete by a crash is cut off when the spool is opened. An entry that can't
+/// be decoded is corrupt: it and everything after it is dropped, the records before it are kept.
+pub struct LocationSpool {
+    file: File,
+    max_bytes: u64,
+    len: u64,
+    replayed: u64,
+    capped: bool,
+}
+
+impl LocationSpool {
+    pub fn open(config: &SpoolConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&config.path)?;
+        let len = Self::complete_len(&file)?;
+        if len < file.metadata()?.len() {
+            warn!("Truncating incomplete entry at the end of the location log spool");
+            file.set_len(len)?;
+            SPOOL_METRICS.dropped_records.fetch_add(1, Ordering::Relaxed);
+        }
+        SPOOL_METRICS.spool_bytes.store(len, Ordering::Relaxed);
+        Ok(Self { file, max_bytes: config.max_bytes, len, replayed: 0, capped: false })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replayed >= self.len
+    }
+
+    /// Appends a record, dropping it if the spool is full.
+    pub fn append(&mut self, record: &PendingRecord) -> io::Result<()> {
+        let payload = rmp_serde::to_vec(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let size = 4 + payload.len() as u64;
+        if self.len + size > self.max_bytes {
+            if !self.capped {
+                warn!("Location log spool is full ({} bytes), dropping records", self.len);
+                self.capped = true;
+            }
+            SPOOL_METRICS.dropped_records.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let mut entry = Vec::with_capacity(size as usize);
+        entry.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&payload);
+        if let Err(e) = self.file.write_all(&entry) {
+            // Leaves no partial entry behind for the next append to be read as part of
+            self.file.set_len(self.len).ok();
+            return Err(e);
+        }
+
+        self.len += size;
+        self.capped = false;
+        SPOOL_METRICS.spool_bytes.store(self.len - self.replayed, Ordering::Relaxed);
+        SPOOL_METRICS.spooled_records.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reads up to `max_records` records that haven't been replayed yet, along with their size on disk.
+    ///
+    /// Drops the spool from the first corrupt entry on, returning the records before it.
+    pub fn read_chunk(&mut self, max_records: usize) -> io::Result<Vec<(PendingRecord, u64)>> {
+        self.file.seek(SeekFrom::Start(self.replayed))?;
+        let remaining = self.len - self.replayed;
+        let mut reader = io::BufReader::new(&self.file).take(remaining);
+        let mut records = Vec::new();
+        let mut consumed = 0;
+        let mut corrupt = None;
+
+        while records.len() < max_records && consumed < remaining {
+            match Self::read_entry(&mut reader) {
+                Ok((record, size)) => {
+                    consumed += size;
+                    records.push((record, size));
+                }
+                // Running out of bytes mid-entry, which read_exact reports as UnexpectedEof, means the
+                // framing is broken as well
+                Err(e) if matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+                    corrupt = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(e) = corrupt {
+            self.drop_from(self.replayed + consumed, &e)?;
+        }
+        Ok(records)
+    }
+
+    fn read_entry(reader: &mut impl Read) -> io::Result<(PendingRecord, u64)> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut payload)?;
+        let record = rmp_serde::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((record, 4 + payload.len() as u64))
+    }
+
+    /// Length of the file up to the end of its last complete entry.
+    fn complete_len(file: &File) -> io::Result<u64> {
+        let len = file.metadata()?.len();
+        let mut reader = io::BufReader::new(file);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut complete = 0;
+        let mut prefix = [0u8; 4];
+        while complete + 4 <= len {
+            reader.read_exact(&mut prefix)?;
+            let end = complete + 4 + u32::from_le_bytes(prefix) as u64;
+            if end > len {
+                break;
+            }
+            reader.seek_relative(end as i64 - complete as i64 - 4)?;
+            complete = end;
+        }
+        Ok(complete)
+    }
+
+    /// Drops the corrupt entry at `offset` and everything after it, counting the entries it can still frame.
+    fn drop_from(&mut self, offset: u64, reason: &io::Error) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut reader = io::BufReader::new(&self.file);
+        let (mut position, mut dropped) = (offset, 0);
+        let mut prefix = [0u8; 4];
+        while position < self.len {
+            dropped += 1;
+            if reader.read_exact(&mut prefix).is_err() {
+                break;
+            }
+            let size = u32::from_le_bytes(prefix) as u64;
+            position += 4 + size;
+            if position < self.len {
+                reader.seek_relative(size as i64)?;
+            }
+        }
+        error!("Dropping {} corrupt location log records from the spool: {}", dropped, reason);
+
+        self.file.set_len(offset)?;
+        self.len = offset;
+        SPOOL_METRICS.dropped_records.fetch_add(dropped, Ordering::Relaxed);
+        SPOOL_METRICS.spool_bytes.store(self.len - self.replayed, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Marks `bytes` more of the spool as replayed, truncating the file once all of it is.
+    pub fn advance(&mut self, bytes: u64, records: u64) -> io::Result<()> {
+        self.replayed = (self.replayed + bytes).min(self.len);
+        SPOOL_METRICS.replayed_records.fetch_add(records, Ordering::Relaxed);
+        if self.is_empty() {
+            self.discard()?;
+        }
+        SPOOL_METRICS.spool_bytes.store(self.len - self.replayed, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drops everything left in the spool.
+    pub fn discard(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        self.replayed = 0;
+        SPOOL_METRICS.spool_bytes.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use crate::handlers::location_logger::PendingRecord;
+    use super::{LocationSpool, SpoolConfig};
+
+    fn config(name: &str) -> SpoolConfig {
+        let path: PathBuf = std::env::temp_dir().join(format!("location_spool_{}_{}.spool", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        SpoolConfig { path, max_bytes: 1024 * 1024 }
+    }
+
+    fn spool_records(config: &SpoolConfig, payloads: &[&[u8]]) {
+        let mut spool = LocationSpool::open(config).unwrap();
+        for payload in payloads {
+            spool.append(&PendingRecord::new("location_log", payload.to_vec())).unwrap();
+        }
+    }
+
+    fn replay(config: &SpoolConfig) -> Vec<Vec<u8>> {
+        let mut spool = LocationSpool::open(config).unwrap();
+        let records = spool.read_chunk(100).unwrap();
+        records.into_iter().map(|(record, _)| record.payload().to_vec()).collect()
+    }
+
+    #[test]
+    fn replays_records_in_order_after_reopening() {
+        let config = config("reopen");
+        spool_records(&config, &[b"first", b"second"]);
+        spool_records(&config, &[b"third"]);
+        assert_eq!(replay(&config), [b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+        std::fs::remove_file(&config.path).ok();
+    }
+
+    #[test]
+    fn cuts_off_a_truncated_entry_and_keeps_the_others() {
+        let config = config("truncated");
+        spool_records(&config, &[b"first", b"second"]);
+        // A crash in the middle of an append leaves the length prefix with part of the payload
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+
+        assert_eq!(replay(&config), [b"first".to_vec(), b"second".to_vec()]);
+        // The spool appends after the last complete entry
+        spool_records(&config, &[b"third"]);
+        assert_eq!(replay(&config), [b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+        std::fs::remove_file(&config.path).ok();
+    }
+
+    #[test]
+    fn drops_a_corrupt_entry_and_keeps_the_ones_before_it() {
+        let config = config("corrupt");
+        spool_records(&config, &[b"first"]);
+        let first_len = std::fs::metadata(&config.path).unwrap().len();
+        spool_records(&config, &[b"second", b"third"]);
+        // Overwrites the second entry's payload with bytes that don't decode, keeping its framing
+        let mut file = OpenOptions::new().write(true).open(&config.path).unwrap();
+        file.seek(SeekFrom::Start(first_len + 4)).unwrap();
+        file.write_all(&[0xc1; 4]).unwrap();
+        drop(file);
+
+        assert_eq!(replay(&config), [b"first".to_vec()]);
+        assert_eq!(std::fs::metadata(&config.path).unwrap().len(), first_len);
+        std::fs::remove_file(&config.path).ok();
+    }
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::time::Instant;
+use once_cell::sync::Lazy;
+use crate::models::position::{Distance, Position};
+
+pub struct OdometerConfig {
+    /// Moves shorter than this are treated as GPS jitter and not counted
+    pub min_step_m: f64,
+    /// Moves faster than this are treated as GPS jumps and not counted
+    pub max_speed_mps: f64,
+}
+
+impl OdometerConfig {
+    pub fn init() -> Self {
+        let number = |name: &str, default: f64| env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .unwrap_or(default);
+
+        Self {
+            min_step_m: number("ODOMETER_MIN_STEP_M", 10.0),
+            max_speed_mps: number("ODOMETER_MAX_SPEED_MPS", 50.0),
+        }
+    }
+}
+
+pub static ODOMETER_CONFIG: Lazy<OdometerConfig> = Lazy::new(OdometerConfig::init);
+
+/// Adds up the distance between the courier's positions, skipping jitter and jumps.
+pub struct Odometer {
+    min_step_m: f64,
+    max_speed_mps: f64,
+    last: Option<(Position, Instant)>,
+}
+
+impl Odometer {
+    pub fn new(config: &OdometerConfig) -> Self {
+        Self { min_step_m: config.min_step_m, max_speed_mps: config.max_speed_mps, last: None }
+    }
+
+    /// Returns the distance travelled since the last counted position, if this one counts.
+    pub fn update(&mut self, pos: &Position, now: Instant) -> Option<Distance> {
+        let Some((last, at)) = self.last else {
+            self.last = Some((*pos, now));
+            return None;
+        };
+        let step = last.distance_to(pos);
+        if step.meters() < self.min_step_m {
+            return None;
+        }
+        // A jump replaces the last position, so the courier isn't stuck behind a bad fix
+        self.last = Some((*pos, now));
+        let secs = now.duration_since(at).as_secs_f64();
+        if step.meters() / secs > self.max_speed_mps {
+            return None;
+        }
+        Some(step)
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::env;
+use std::path::PathBuf;
+use dashmap::DashMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::error;
+
+/// Returns `name` if it can be used as a file name as is. Order ids end up in file names, so only
+/// plain identifiers are accepted.
+pub fn safe_file_component(name: &str) -> Option<&str> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
+/// Entries recorded for active orders, kept in memory up to a maximum per order, dropping the
+/// oldest ones beyond it. Once the order completes they are written to a directory as a JSON
+/// document per order.
+pub struct OrderStore<T> {
+    /// What the store keeps, for log messages
+    kind: &'static str,
+    orders: DashMap<String, VecDeque<T>>,
+    max_entries: usize,
+    /// Entries that are never dropped to make room for newer ones
+    retained: fn(&T) -> bool,
+    dir: PathBuf,
+}
+
+impl<T: Clone> OrderStore<T> {
+    /// Creates a store configured by the `max_entries_var` and `dir_var` environment variables.
+    pub fn init(kind: &'static str, max_entries_var: &str, max_entries: usize, dir_var: &str, dir: &str) -> Self {
+        Self {
+            kind,
+            orders: DashMap::new(),
+            max_entries: env::var(max_entries_var)
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(max_entries),
+            retained: |_| false,
+            dir: env::var(dir_var).unwrap_or_else(|_| dir.to_string()).into(),
+        }
+    }
+
+    /// Keeps the entries `retained` is true for when the maximum is reached, dropping the oldest
+    /// other entry instead. Once only retained entries are left, other entries are no longer recorded.
+    pub fn retaining(self, retained: fn(&T) -> bool) -> Self {
+        Self { retained, ..self }
+    }
+
+    pub fn record(&self, order_id: &str, entry: T) {
+        let mut entries = self.orders.entry(order_id.to_string()).or_default();
+        if entries.len() >= self.max_entries {
+            match entries.iter().position(|entry| !(self.retained)(entry)) {
+                Some(oldest) => {
+                    entries.remove(oldest);
+                }
+                None if !(self.retained)(&entry) => return,
+                None => {}
+            }
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries of an active order.
+    pub fn entries(&self, order_id: &str) -> Option<Vec<T>> {
+        self.orders.get(order_id).map(|entries| entries.iter().cloned().collect())
+    }
+
+    /// Takes the entries of a finished order out of memory.
+    pub fn remove(&self, order_id: &str) -> Option<(String, Vec<T>)> {
+        self.orders.remove(order_id).map(|(order_id, entries)| (order_id, entries.into()))
+    }
+
+    /// Reads the document written for a finished order.
+    pub async fn load<D: DeserializeOwned>(&self, order_id: &str) -> Option<D> {
+        let contents = tokio::fs::read(self.path(order_id)?).await.ok()?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| error!("Corrupted {} of order {}: {}", self.kind, order_id, e))
+            .ok()
+    }
+
+    /// Writes the document of a finished order, logging failures.
+    pub async fn write<D: Serialize>(&self, order_id: &str, document: &D) {
+        let Some(path) = self.path(order_id) else {
+            error!("Not persisting {} of order {}: invalid order id", self.kind, order_id);
+            return;
+        };
+        let result = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(path, serde_json::to_vec(document)?).await
+        }.await;
+        if let Err(e) = result {
+            error!("Failed to persist {} of order {}: {}", self.kind, order_id, e);
+        }
+    }
+
+    fn path(&self, order_id: &str) -> Option<PathBuf> {
+        safe_file_component(order_id).map(|order_id| self.dir.join(format!("{}.json", order_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{safe_file_component, OrderStore};
+
+    #[test]
+    fn accepts_only_plain_identifiers_as_file_names() {
+        assert_eq!(safe_file_component("order-42_a"), Some("order-42_a"));
+        for name in ["", "..", "../etc", "a/b", "a b", "ä"] {
+            assert_eq!(safe_file_component(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_entries_beyond_the_maximum() {
+        let store = OrderStore { kind: "test", orders: Default::default(), max_entries: 3, retained: |_| false, dir: "unused".into() };
+        for entry in 0..5 {
+            store.record("order", entry);
+        }
+        assert_eq!(store.entries("order"), Some(vec![2, 3, 4]));
+        assert_eq!(store.remove("order"), Some(("order".to_string(), vec![2, 3, 4])));
+        assert_eq!(store.entries("order"), None);
+    }
+
+    #[test]
+    fn keeps_retained_entries_beyond_the_maximum() {
+        let store = OrderStore { kind: "test", orders: Default::default(), max_entries: 3, retained: |_| false, dir: "unused".into() }
+            .retaining(|entry| entry % 10 == 0);
+        for entry in [0, 1, 2, 3, 10, 4, 20, 30, 5] {
+            store.record("order", entry);
+        }
+        // Once only retained entries are left, retained ones are still added and others aren't
+        assert_eq!(store.entries("order"), Some(vec![0, 10, 20, 30]));
+    }
+}
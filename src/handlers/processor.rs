@@ -1,7 +1,22 @@
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use crate::handlers::alert_publisher::ALERT_METRICS;
 use crate::handlers::events::TypedCommand;
-use crate::models::updates::{order_created, order_in_transit, OrderDelivered, OrderCreated, OrderInTransit, OrderState};
+use crate::handlers::geofence::{GEOFENCE_CONFIG, GeofenceEvent, GeofenceMode};
+use crate::handlers::proof_of_delivery::{PROOF_CONFIG, ProofStore};
+use crate::handlers::stall_detector::{STALL_CONFIG, StallEvent};
+use crate::handlers::states::StateKind;
+use crate::handlers::track_store::TrackStore;
+use crate::models::updates::{id_verification, order_created, order_in_transit, substitution_approval, IdVerification,
+                             OrderDelivered, OrderCreated, OrderInTransit, OrderState, SubstitutionApproval};
 use async_trait::async_trait;
-use crate::models::location_log::LocationLog;
+use tracing::error;
+use crate::models::alert::Alert;
+use crate::models::error::{ErrorCode, UpdateError};
+use crate::models::location_log::LocationLogEnvelope;
+use crate::models::order_outcome::Leg;
+use crate::models::proof::{Proof, ProofKind, ProofOfDelivery};
+use crate::models::track::TrackPoint;
 use crate::models::updates::order_completed::InboundCustomerUpdate;
 
 #[async_trait]
@@ -25,12 +40,67 @@ impl UpdateProcessor<OrderCreated> for WebSocketUpdateProcessor<OrderCreated> {
     async fn process_courier_update(&mut self, update: <OrderCreated as OrderState>::InboundCourierUpdate) -> Vec<TypedCommand<OrderCreated>> {
         match update {
             order_created::InboundCourierUpdate::TookOrder
-            => vec![TypedCommand::Transition(crate::handlers::events::StateKind::OrderInTransit)],
+            => vec![TypedCommand::Transition(StateKind::OrderInTransit)],
+            order_created::InboundCourierUpdate::InTransit(pos) => {
+                let now = Instant::now();
+                let entered_pickup = self.state.pickup.as_mut()
+                    .and_then(|pickup| pickup.update(&pos, now))
+                    == Some(GeofenceEvent::Entered);
+                let mut commands: Vec<_> = self.state.odometer.lock().unwrap().update(&pos, now)
+                    .map(|distance| TypedCommand::RecordDistance(Leg::Pickup, distance))
+                    .into_iter()
+                    .collect();
+                match GEOFENCE_CONFIG.pickup_mode {
+                    GeofenceMode::Auto if entered_pickup =>
+                        commands.push(TypedCommand::Transition(StateKind::OrderInTransit)),
+                    GeofenceMode::Suggest if entered_pickup => {
+                        commands.push(TypedCommand::SendCourierNotify(order_created::OutboundCourierUpdate::SuggestTookOrder));
+                        commands.push(TypedCommand::ProcessedCourierUpdate);
+                    }
+                    _ => commands.push(TypedCommand::ProcessedCourierUpdate),
+                }
+                commands
+            }
         }
     }
 
-    async fn process_customer_update(&mut self, update: <OrderCreated as OrderState>::InboundCustomerUpdate) -> Vec<TypedCommand<OrderCreated>> {
-        vec![]
+    async fn process_customer_update(&mut self, _update: <OrderCreated as OrderState>::InboundCustomerUpdate) -> Vec<TypedCommand<OrderCreated>> {
+        vec![TypedCommand::CustomerError(UpdateError::not_allowed_in_state(OrderCreated::state_name()))]
+    }
+}
+
+#[async_trait]
+impl UpdateProcessor<SubstitutionApproval> for WebSocketUpdateProcessor<SubstitutionApproval> {
+    async fn process_courier_update(&mut self, update: <SubstitutionApproval as OrderState>::InboundCourierUpdate)
+                                    -> Vec<TypedCommand<SubstitutionApproval>> {
+        match update {
+            substitution_approval::InboundCourierUpdate::InTransit(_) => vec![TypedCommand::ProcessedCourierUpdate],
+            substitution_approval::InboundCourierUpdate::ProposeSubstitution(substitution) => {
+                self.state.pending.insert(substitution.item.clone(), substitution.clone());
+                vec![TypedCommand::SendCustomerNotify(substitution_approval::OutboundCustomerUpdate::SubstitutionProposed(substitution)),
+                     TypedCommand::ProcessedCourierUpdate]
+            }
+            substitution_approval::InboundCourierUpdate::PickedUp if !self.state.pending.is_empty() =>
+                vec![TypedCommand::CourierError(UpdateError::new(
+                    ErrorCode::NotAllowedInState,
+                    format!("{} substitutions awaiting approval", self.state.pending.len())))],
+            substitution_approval::InboundCourierUpdate::PickedUp =>
+                vec![TypedCommand::Transition(StateKind::OrderInTransit)],
+        }
+    }
+
+    async fn process_customer_update(&mut self, update: <SubstitutionApproval as OrderState>::InboundCustomerUpdate)
+                                     -> Vec<TypedCommand<SubstitutionApproval>> {
+        let (item, notify) = match update {
+            substitution_approval::InboundCustomerUpdate::ApproveSubstitution(item) =>
+                (item.clone(), substitution_approval::OutboundCourierUpdate::SubstitutionApproved(item)),
+            substitution_approval::InboundCustomerUpdate::RejectSubstitution(item) =>
+                (item.clone(), substitution_approval::OutboundCourierUpdate::SubstitutionRejected(item)),
+        };
+        match self.state.pending.remove(&item) {
+            Some(_) => vec![TypedCommand::SendCourierNotify(notify), TypedCommand::ProcessedCustomerUpdate],
+            None => vec![TypedCommand::CustomerError(UpdateError::invalid_message(format!("No substitution proposed for {}", item)))],
+        }
     }
 }
 
@@ -40,29 +110,188 @@ impl UpdateProcessor<OrderInTransit> for WebSocketUpdateProcessor<OrderInTransit
                                     -> Vec<TypedCommand<OrderInTransit>> {
         match update {
             order_in_transit::InboundCourierUpdate::InTransit(pos) => {
-                self.state.logger.send((self.state.order_id.clone(), LocationLog::new(pos.lat, pos.lon)))
-                    .await.map_err(|_| "Failed to log location").unwrap();
-                vec![TypedCommand::SendCustomerNotify(
-                    crate::models::updates::order_in_transit::OutboundCustomerUpdate::InTransit(pos)),
+                let now = Instant::now();
+                if self.state.throttle.should_log(&pos, now) {
+                    let point = TrackPoint::now(&pos);
+                    let envelope = LocationLogEnvelope {
+                        order_id: self.state.order_id.to_string(),
+                        courier_id: self.state.courier_id.to_string(),
+                        timestamp: point.timestamp,
+                        lat: pos.lat,
+                        lon: pos.lon,
+                    };
+                    TrackStore::record(&self.state.order_id, point);
+                    if self.state.logger.send(envelope).await.is_err() {
+                        error!("Failed to log location of order {}", self.state.order_id);
+                    }
+                }
+                let mut commands = Vec::with_capacity(4);
+                let mut delivered = false;
+                let distance = self.state.odometer.lock().unwrap().update(&pos, now)
+                    .map(|distance| TypedCommand::RecordDistance(Leg::Delivery, distance));
+                if self.state.throttle.should_notify(&pos, now) {
+                    commands.push(TypedCommand::SendCustomerNotify(
+                        crate::models::updates::order_in_transit::OutboundCustomerUpdate::InTransit(pos)));
+                }
+                if let Some(dropoff) = self.state.dropoff.as_mut() {
+                    match dropoff.update(&pos, now) {
+                        Some(GeofenceEvent::Entered) => commands.push(TypedCommand::SendCustomerNotify(
+                            order_in_transit::OutboundCustomerUpdate::OrderNearby(dropoff.distance_to_center(&pos)))),
+                        Some(GeofenceEvent::Dwelled) => match GEOFENCE_CONFIG.dropoff_mode {
+                            GeofenceMode::Auto if self.has_proof() => delivered = true,
+                            _ => commands.push(TypedCommand::SendCourierNotify(
+                                order_in_transit::OutboundCourierUpdate::ConfirmArrival)),
+                        },
+                        None => {}
+                    }
+                }
+                if let Some((distance, off_route)) = self.state.route_deviation.as_mut()
+                    .and_then(|detector| detector.update(&pos, now)) {
+                    let alert = Alert::RouteDeviation {
+                        order_id: self.state.order_id.to_string(),
+                        courier_id: self.state.courier_id.to_string(),
+                        position: pos,
+                        distance_m: distance.meters(),
+                        off_route_secs: off_route.as_secs(),
+                    };
+                    self.publish_alert(alert);
+                    commands.push(TypedCommand::SendCourierNotify(
+                        order_in_transit::OutboundCourierUpdate::RouteDeviation(distance)));
+                }
+                match self.state.stall.update(&pos, now) {
+                    Some(StallEvent::Stalled(stationary)) => {
+                        self.publish_alert(Alert::CourierStalled {
+                            order_id: self.state.order_id.to_string(),
+                            courier_id: self.state.courier_id.to_string(),
+                            position: pos,
+                            stationary_secs: stationary.as_secs(),
+                        });
+                        if STALL_CONFIG.notify_customer {
+                            commands.push(TypedCommand::SendCustomerNotify(order_in_transit::OutboundCustomerUpdate::Delayed));
+                        }
+                    }
+                    Some(StallEvent::Resumed) => {
+                        self.publish_alert(Alert::CourierResumed {
+                            order_id: self.state.order_id.to_string(),
+                            courier_id: self.state.courier_id.to_string(),
+                            position: pos,
+                        });
+                        if STALL_CONFIG.notify_customer {
+                            commands.push(TypedCommand::SendCustomerNotify(order_in_transit::OutboundCustomerUpdate::Resumed));
+                        }
+                    }
+                    None => {}
+                }
+                commands.extend(distance);
+                commands.push(if delivered {
+                    TypedCommand::Transition(StateKind::OrderDelivered)
+                } else {
+                    TypedCommand::ProcessedCourierUpdate
+                });
+                commands
+            }
+            order_in_transit::InboundCourierUpdate::SubmitProof(proof) => self.submit_proof(proof).await,
+            order_in_transit::InboundCourierUpdate::CourierStatus(status) => {
+                self.publish_alert(Alert::CourierStatus {
+                    order_id: self.state.order_id.to_string(),
+                    courier_id: self.state.courier_id.to_string(),
+                    status,
+                });
+                vec![TypedCommand::SendCustomerNotify(order_in_transit::OutboundCustomerUpdate::CourierStatus(status)),
                      TypedCommand::ProcessedCourierUpdate]
             }
+            order_in_transit::InboundCourierUpdate::Delivered if !self.has_proof() =>
+                vec![TypedCommand::CourierError(UpdateError::new(
+                    ErrorCode::NotAllowedInState,
+                    format!("Proof of delivery required: {}", PROOF_CONFIG.requirement)))],
             order_in_transit::InboundCourierUpdate::Delivered => {
-                vec![TypedCommand::Transition(crate::handlers::events::StateKind::OrderDelivered)]
+                vec![TypedCommand::Transition(StateKind::OrderDelivered)]
             }
         }
     }
 
-    async fn process_customer_update(&mut self, update: <OrderInTransit as OrderState>::InboundCustomerUpdate)
+    async fn process_customer_update(&mut self, _update: <OrderInTransit as OrderState>::InboundCustomerUpdate)
                                      -> Vec<TypedCommand<OrderInTransit>> {
-        vec![]
+        vec![TypedCommand::CustomerError(UpdateError::not_allowed_in_state(OrderInTransit::state_name()))]
+    }
+}
+
+impl WebSocketUpdateProcessor<OrderInTransit> {
+    /// Queues an alert for publishing, dropping it rather than holding up the update when the queue is full.
+    fn publish_alert(&self, alert: Alert) {
+        if let Err(e) = self.state.alerts.try_send(alert) {
+            ALERT_METRICS.dropped_alerts.fetch_add(1, Ordering::Relaxed);
+            error!("Dropped alert for order {}: {}", self.state.order_id, e);
+        }
+    }
+
+    fn has_proof(&self) -> bool {
+        PROOF_CONFIG.requirement.is_satisfied_by(self.state.proof.as_ref())
+    }
+
+    /// Checks the proof against the uploads or the handoff PIN and records it for the processed order.
+    async fn submit_proof(&mut self, proof: Proof) -> Vec<TypedCommand<OrderInTransit>> {
+        let (kind, reference) = match proof {
+            Proof::Photo(reference) => (ProofKind::Photo, Some(reference)),
+            Proof::Signature(reference) => (ProofKind::Signature, Some(reference)),
+            Proof::Pin(pin) => {
+                let Some(expected) = self.state.pin.as_ref() else {
+                    return vec![TypedCommand::CourierError(UpdateError::new(
+                        ErrorCode::NotAllowedInState, "Order has no handoff PIN".to_string()))];
+                };
+                if self.state.pin_attempts.load(Ordering::Relaxed) >= PROOF_CONFIG.pin_max_attempts {
+                    return vec![TypedCommand::CourierError(UpdateError::new(
+                        ErrorCode::NotAllowedInState, "Too many wrong PINs".to_string()))];
+                }
+                if pin != **expected {
+                    self.state.pin_attempts.fetch_add(1, Ordering::Relaxed);
+                    return vec![TypedCommand::CourierError(UpdateError::invalid_message("Wrong PIN".to_string()))];
+                }
+                (ProofKind::Pin, None)
+            }
+        };
+        if !PROOF_CONFIG.requirement.accepts(kind) {
+            return vec![TypedCommand::CourierError(UpdateError::new(
+                ErrorCode::NotAllowedInState,
+                format!("Proof of delivery required: {}", PROOF_CONFIG.requirement)))];
+        }
+        if let Some(reference) = reference.as_deref() {
+            if !ProofStore::contains(&self.state.order_id, kind, reference).await {
+                return vec![TypedCommand::CourierError(UpdateError::invalid_message(
+                    format!("No {} uploaded as {}", kind.name(), reference)))];
+            }
+        }
+
+        let proof = ProofOfDelivery { kind, reference };
+        self.state.proof = Some(proof.clone());
+        vec![TypedCommand::RecordProof(proof), TypedCommand::ProcessedCourierUpdate]
+    }
+}
+
+#[async_trait]
+impl UpdateProcessor<IdVerification> for WebSocketUpdateProcessor<IdVerification> {
+    async fn process_courier_update(&mut self, update: <IdVerification as OrderState>::InboundCourierUpdate)
+                                    -> Vec<TypedCommand<IdVerification>> {
+        match update {
+            id_verification::InboundCourierUpdate::IdVerified =>
+                vec![TypedCommand::Transition(StateKind::OrderDelivered)],
+            id_verification::InboundCourierUpdate::IdRejected =>
+                vec![TypedCommand::SendCustomerNotify(id_verification::OutboundCustomerUpdate::IdVerificationFailed),
+                     TypedCommand::ProcessedCourierUpdate],
+        }
+    }
+
+    async fn process_customer_update(&mut self, _update: <IdVerification as OrderState>::InboundCustomerUpdate)
+                                     -> Vec<TypedCommand<IdVerification>> {
+        vec![TypedCommand::CustomerError(UpdateError::not_allowed_in_state(IdVerification::state_name()))]
     }
 }
 
 #[async_trait]
 impl UpdateProcessor<OrderDelivered> for WebSocketUpdateProcessor<OrderDelivered> {
-    async fn process_courier_update(&mut self, update: <OrderDelivered as OrderState>::InboundCourierUpdate)
+    async fn process_courier_update(&mut self, _update: <OrderDelivered as OrderState>::InboundCourierUpdate)
                                     -> Vec<TypedCommand<OrderDelivered>> {
-        vec![]
+        vec![TypedCommand::CourierError(UpdateError::not_allowed_in_state(OrderDelivered::state_name()))]
     }
 
     async fn process_customer_update(&mut self, update: <OrderDelivered as OrderState>::InboundCustomerUpdate)
@@ -0,0 +1,198 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use crate::handlers::order_store::safe_file_component;
+use crate::models::proof::{ProofKind, ProofOfDelivery};
+
+/// Proof the courier has to submit before an order counts as delivered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProofRequirement {
+    /// Orders are delivered without proof. Proof that is submitted anyway is still recorded.
+    None,
+    Photo,
+    Signature,
+    Pin,
+    /// Any kind of proof.
+    Any,
+}
+
+impl ProofRequirement {
+    /// Reads the requirement from `name`, none if unset. A value that isn't understood
+    /// requires proof of any kind rather than none.
+    fn from_env(name: &str) -> Self {
+        match env::var(name).as_deref() {
+            Ok("photo") => ProofRequirement::Photo,
+            Ok("signature") => ProofRequirement::Signature,
+            Ok("pin") => ProofRequirement::Pin,
+            Ok("any") => ProofRequirement::Any,
+            Ok("none") | Err(_) => ProofRequirement::None,
+            Ok(value) => {
+                warn!("Unknown {} {}, requiring any proof", name, value);
+                ProofRequirement::Any
+            }
+        }
+    }
+
+    /// Whether proof of this kind is accepted from the courier.
+    pub fn accepts(&self, kind: ProofKind) -> bool {
+        match self {
+            ProofRequirement::None | ProofRequirement::Any => true,
+            ProofRequirement::Photo => kind == ProofKind::Photo,
+            ProofRequirement::Signature => kind == ProofKind::Signature,
+            ProofRequirement::Pin => kind == ProofKind::Pin,
+        }
+    }
+
+    pub fn is_satisfied_by(&self, proof: Option<&ProofOfDelivery>) -> bool {
+        *self == ProofRequirement::None || proof.is_some_and(|proof| self.accepts(proof.kind))
+    }
+
+    /// Whether orders get a handoff PIN for the customer.
+    pub fn uses_pin(&self) -> bool {
+        matches!(self, ProofRequirement::Pin | ProofRequirement::Any)
+    }
+}
+
+impl Display for ProofRequirement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ProofRequirement::None => "none",
+            ProofRequirement::Photo => "photo",
+            ProofRequirement::Signature => "signature",
+            ProofRequirement::Pin => "PIN",
+            ProofRequirement::Any => "photo, signature or PIN",
+        })
+    }
+}
+
+pub struct ProofConfig {
+    pub requirement: ProofRequirement,
+    /// Directory uploaded photos and signatures are stored in
+    pub store_dir: PathBuf,
+    pub max_upload_bytes: usize,
+    pub pin_length: usize,
+    /// Wrong PINs the courier may submit before PIN proof is refused for the order
+    pub pin_max_attempts: u32,
+}
+
+impl ProofConfig {
+    pub fn init() -> Self {
+        let number = |name: &str, default: usize| env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(default);
+
+        Self {
+            requirement: ProofRequirement::from_env("PROOF_OF_DELIVERY"),
+            store_dir: env::var("PROOF_STORE_DIR")
+                .unwrap_or_else(|_| "proofs".to_string())
+                .into(),
+            max_upload_bytes: number("PROOF_MAX_UPLOAD_BYTES", 5 * 1024 * 1024),
+            pin_length: number("PROOF_PIN_LENGTH", 4).clamp(1, 9),
+            pin_max_attempts: number("PROOF_PIN_MAX_ATTEMPTS", 5) as u32,
+        }
+    }
+}
+
+pub static PROOF_CONFIG: Lazy<ProofConfig> = Lazy::new(ProofConfig::init);
+
+/// Generates the PIN the customer gives the courier at handoff.
+pub fn generate_pin(config: &ProofConfig) -> String {
+    let pin = rand::thread_rng().gen_range(0..10u32.pow(config.pin_length as u32));
+    format!("{:0width$}", pin, width = config.pin_length)
+}
+
+/// Stores uploaded photos and signatures in `PROOF_STORE_DIR`, one directory per order.
+///
+/// Uploads are referred to as `{order_id}/{kind}-{timestamp}-{suffix}`, the reference the courier submits
+/// as proof. The random hex suffix tells apart uploads made in the same millisecond.
+pub struct ProofStore;
+
+impl ProofStore {
+    pub async fn save(order_id: &str, kind: ProofKind, contents: &[u8]) -> std::io::Result<String> {
+        let dir = Self::dir(order_id)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid order id"))?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let name = format!("{}-{}-{:08x}", kind.name(), timestamp, rand::thread_rng().gen::<u32>());
+
+        tokio::fs::create_dir_all(&dir).await?;
+        // Refuses to overwrite an upload should the suffix collide as well
+        let mut file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(dir.join(&name)).await?;
+        file.write_all(contents).await?;
+        Ok(format!("{}/{}", order_id, name))
+    }
+
+    /// Whether `reference` is an upload of the given kind for the order.
+    pub async fn contains(order_id: &str, kind: ProofKind, reference: &str) -> bool {
+        let Some(name) = reference.strip_prefix(order_id).and_then(|name| name.strip_prefix('/')) else {
+            return false;
+        };
+        let valid = name.strip_prefix(kind.name())
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.split_once('-'))
+            .is_some_and(|(timestamp, suffix)| !timestamp.is_empty() && timestamp.chars().all(|c| c.is_ascii_digit())
+                && !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_hexdigit()));
+        match Self::dir(order_id) {
+            Some(dir) if valid => tokio::fs::metadata(dir.join(name)).await.is_ok(),
+            _ => false,
+        }
+    }
+
+    fn dir(order_id: &str) -> Option<PathBuf> {
+        safe_file_component(order_id).map(|order_id| PROOF_CONFIG.store_dir.join(order_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::proof::{ProofKind, ProofOfDelivery};
+    use super::{ProofRequirement, ProofStore, PROOF_CONFIG};
+
+    fn proof(kind: ProofKind) -> ProofOfDelivery {
+        ProofOfDelivery { kind, reference: None }
+    }
+
+    #[test]
+    fn gates_delivery_on_the_required_proof() {
+        assert!(ProofRequirement::None.is_satisfied_by(None));
+        assert!(!ProofRequirement::Any.is_satisfied_by(None));
+        assert!(ProofRequirement::Any.is_satisfied_by(Some(&proof(ProofKind::Signature))));
+        assert!(ProofRequirement::Photo.is_satisfied_by(Some(&proof(ProofKind::Photo))));
+        assert!(!ProofRequirement::Photo.is_satisfied_by(Some(&proof(ProofKind::Pin))));
+        assert!(!ProofRequirement::Pin.accepts(ProofKind::Photo));
+        assert!(ProofRequirement::None.accepts(ProofKind::Pin));
+    }
+
+    #[test]
+    fn uses_a_pin_only_when_pins_are_accepted() {
+        assert!(ProofRequirement::Pin.uses_pin());
+        assert!(ProofRequirement::Any.uses_pin());
+        assert!(!ProofRequirement::Photo.uses_pin());
+        assert!(!ProofRequirement::None.uses_pin());
+    }
+
+    #[tokio::test]
+    async fn finds_saved_uploads_by_their_reference_only() {
+        let order_id = format!("proof-test-{}", std::process::id());
+        let reference = ProofStore::save(&order_id, ProofKind::Photo, b"jpeg").await.unwrap();
+        let other = ProofStore::save(&order_id, ProofKind::Photo, b"jpeg").await.unwrap();
+        assert_ne!(reference, other);
+
+        assert!(ProofStore::contains(&order_id, ProofKind::Photo, &reference).await);
+        assert!(!ProofStore::contains(&order_id, ProofKind::Signature, &reference).await);
+        assert!(!ProofStore::contains("another-order", ProofKind::Photo, &reference).await);
+        assert!(!ProofStore::contains(&order_id, ProofKind::Photo, &format!("{}/../photo-1-0", order_id)).await);
+        assert!(ProofStore::save("../escape", ProofKind::Photo, b"jpeg").await.is_err());
+
+        tokio::fs::remove_dir_all(ProofStore::dir(&order_id).unwrap()).await.ok();
+        // Only removed if no other uploads are in there
+        tokio::fs::remove_dir(&PROOF_CONFIG.store_dir).await.ok();
+    }
+}
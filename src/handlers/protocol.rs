@@ -0,0 +1,446 @@
+//! WebSocket wire protocol.
+//!
+//! The protocol version is negotiated through `Sec-WebSocket-Protocol`. Clients list the
+//! versions they speak and the server picks the newest one it supports; clients that don't
+//! send the header get v0.
+//!
+//! # v0 (legacy)
+//!
+//! Messages are the serde representation of the state's update enums, e.g.
+//! `{"InTransit": {"lat": 1.0, "lon": 2.0}}` or `"Delivered"`. Outbound updates carry an extra
+//! `order_state` field, so variants without content are sent as `{"Delayed": null, "order_state": "..."}`,
+//! acknowledgements are the bare string `"PROCESSED"`, transitions are
+//! `{"transition": "<state>"}`, errors are a bare string and completion is `"ORDER_COMPLETE"`.
+//!
+//! # v1 (`foodio.v1`)
+//!
+//! Every message is an envelope:
+//!
+//! ```json
+//! {"v": 1, "type": "InTransit", "seq": 3, "payload": {"lat": 1.0, "lon": 2.0}}
+//! ```
+//!
+//! * `v` - protocol version, always `1`
+//! * `type` - message type: the update variant name, or one of `Processed`, `Transition`,
+//!   `Error`, `ChatReceipt` and `OrderComplete` for server messages
+//! * `seq` - sequence number; outbound messages are numbered from 1 per participant and order
+//!   as the order session produces them, across reconnects. Messages are only kept until a newer
+//!   one replaces them, so a gap means the client missed messages, e.g. a position update while
+//!   it was disconnected. Inbound numbers are chosen by the client and not interpreted
+//! * `payload` - the variant's content, `null` (or absent on inbound messages) for variants without one
+//! * `id` - optional string chosen by the client for an inbound message. The `Processed`,
+//!   `Error`, `Transition` or `ChatReceipt` message answering it carries the same `id`. A message whose id
+//!   was recently accepted is not processed again, the original answer is sent instead. Rejected
+//!   messages are processed again when retried.
+//!
+//! Outbound updates also carry the order state in `state`, and `Transition` carries the new
+//! state as `{"state": "<state>"}`.
+//!
+//! `Error` payloads are `{"code": "<code>", "message": "...", "state": "<state>"}`, where the code
+//! is one of `INVALID_MESSAGE`, `NOT_ALLOWED_IN_STATE`, `RATE_LIMITED` and `CHAT_LIMIT_REACHED`.
+//! v0 only gets the message.
+//!
+//! # Chat
+//!
+//! In every state but the last, both participants can send `{"ChatMessage": {"text": "..."}}`.
+//! The other participant receives it as an update, `{"ChatMessage": {"message_id", "from", "text", "timestamp"}}`,
+//! and the sender is answered with `{"ChatReceipt": {"message_id", "delivered": false}}` instead of
+//! an acknowledgement. Once the other participant's socket sent the message, the sender gets a
+//! second receipt with `delivered: true`. In v1 the first receipt is a `ChatReceipt` message
+//! carrying the `id` of the chat message, the second one has no `id`.
+//!
+//! Unlike other messages, chat messages and receipts are never replaced by newer ones: they are
+//! queued until the participant's socket sent them, so a participant who reconnects gets the
+//! ones they missed. An order takes up to `CHAT_MAX_MESSAGES` chat messages, and a participant
+//! up to `CHAT_MAX_QUEUED` undelivered ones: beyond that, chat messages are refused with
+//! `CHAT_LIMIT_REACHED`.
+//!
+//! # Courier reassignment
+//!
+//! When the order is handed to another courier, the previous courier's socket is closed with
+//! code 4000 and the customer receives `{"CourierReassigned": {"courier_id", "reason"}}` as an
+//! update, carrying the state the order was reset to.
+//!
+//! # Binary codecs
+//!
+//! v1 can also be spoken in MessagePack (`foodio.v1+msgpack`) or CBOR (`foodio.v1+cbor`).
+//! The messages have the same structure as in JSON, but are sent as binary frames. Text
+//! frames are still accepted as JSON on these connections.
+
+use axum::extract::ws::Message;
+use axum::http::HeaderValue;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::handlers::handler::Decode;
+use crate::models::chat::ChatReceipt;
+use crate::models::error::UpdateError;
+
+pub const PROTOCOL_V1: &str = "foodio.v1";
+pub const PROTOCOL_V1_MSGPACK: &str = "foodio.v1+msgpack";
+pub const PROTOCOL_V1_CBOR: &str = "foodio.v1+cbor";
+pub const PROTOCOL_V0: &str = "foodio.v0";
+
+/// Protocols offered during the handshake, most preferred first.
+pub const SUPPORTED_PROTOCOLS: [&str; 4] = [PROTOCOL_V1_MSGPACK, PROTOCOL_V1_CBOR, PROTOCOL_V1, PROTOCOL_V0];
+
+/// A MessagePack-encoded message.
+#[derive(Debug)]
+pub struct MessagePack(pub Vec<u8>);
+
+/// A CBOR-encoded message.
+#[derive(Debug)]
+pub struct Cbor(pub Vec<u8>);
+
+/// Message received from a participant, along with the id the client gave it.
+#[derive(Debug)]
+pub struct Inbound {
+    pub id: Option<String>,
+    pub update: Result<InboundFrame, UpdateError>,
+}
+
+/// Message received from a participant, in the codec it was sent with.
+#[derive(Debug)]
+pub enum InboundFrame {
+    Json(String),
+    MessagePack(MessagePack),
+    Cbor(Cbor),
+}
+
+impl InboundFrame {
+    fn codec(&self) -> WireCodec {
+        match self {
+            InboundFrame::Json(_) => WireCodec::Json,
+            InboundFrame::MessagePack(_) => WireCodec::MessagePack,
+            InboundFrame::Cbor(_) => WireCodec::Cbor,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match self {
+            InboundFrame::Json(message) => message.decode().map_err(|e| e.to_string()),
+            InboundFrame::MessagePack(message) => message.decode().map_err(|e| e.to_string()),
+            InboundFrame::Cbor(message) => message.decode().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireCodec {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl WireCodec {
+    fn serialize(&self, value: &Value) -> Vec<u8> {
+        match self {
+            WireCodec::Json => serde_json::to_vec(value).unwrap(),
+            WireCodec::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            WireCodec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    fn inbound(&self, value: &Value) -> InboundFrame {
+        match self {
+            WireCodec::Json => InboundFrame::Json(value.to_string()),
+            WireCodec::MessagePack => InboundFrame::MessagePack(MessagePack(self.serialize(value))),
+            WireCodec::Cbor => InboundFrame::Cbor(Cbor(self.serialize(value))),
+        }
+    }
+
+    fn outbound(&self, value: &Value) -> Message {
+        match self {
+            WireCodec::Json => Message::Text(value.to_string()),
+            _ => Message::Binary(self.serialize(value)),
+        }
+    }
+}
+
+/// Protocol version and codec negotiated for a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Protocol {
+    pub version: ProtocolVersion,
+    pub codec: WireCodec,
+}
+
+impl Protocol {
+    pub fn negotiated(protocol: Option<&HeaderValue>) -> Self {
+        let (version, codec) = match protocol.and_then(|p| p.to_str().ok()) {
+            Some(PROTOCOL_V1_MSGPACK) => (ProtocolVersion::V1, WireCodec::MessagePack),
+            Some(PROTOCOL_V1_CBOR) => (ProtocolVersion::V1, WireCodec::Cbor),
+            Some(PROTOCOL_V1) => (ProtocolVersion::V1, WireCodec::Json),
+            _ => (ProtocolVersion::V0, WireCodec::Json),
+        };
+        Self { version, codec }
+    }
+
+    /// Turns a WebSocket message into the representation the update handlers deserialize.
+    /// Returns `None` for messages that don't carry an update.
+    pub fn decode(&self, message: Message) -> Option<Inbound> {
+        let frame = match (message, self.codec) {
+            (Message::Text(text), _) => InboundFrame::Json(text),
+            (Message::Binary(_), WireCodec::Json) => return Some(Inbound {
+                id: None,
+                update: Err(UpdateError::invalid_message("Binary messages require a binary codec".to_string())),
+            }),
+            (Message::Binary(bytes), WireCodec::MessagePack) => InboundFrame::MessagePack(MessagePack(bytes)),
+            (Message::Binary(bytes), WireCodec::Cbor) => InboundFrame::Cbor(Cbor(bytes)),
+            _ => return None,
+        };
+        Some(self.version.decode(frame))
+    }
+
+    pub fn encode(&self, message: &Sequenced) -> Message {
+        self.codec.outbound(&self.version.encode(&message.message, message.seq))
+    }
+}
+
+/// Message sent by the order session to a participant, encoded per connection.
+///
+/// `id` is the id of the inbound message the message answers, if the client gave it one.
+#[derive(Clone, Debug)]
+pub enum OutboundMessage {
+    Update { update: Value, order_state: String },
+    Processed { id: Option<String> },
+    Transition { state: String, id: Option<String> },
+    Error { error: Value, order_state: String, id: Option<String> },
+    ChatReceipt { receipt: ChatReceipt, id: Option<String> },
+    OrderComplete,
+}
+
+/// Outbound message numbered by the order session, see `seq` in the v1 envelope.
+#[derive(Clone, Debug)]
+pub struct Sequenced {
+    pub seq: u64,
+    pub message: OutboundMessage,
+}
+
+impl OutboundMessage {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            OutboundMessage::Processed { id }
+            | OutboundMessage::Transition { id, .. }
+            | OutboundMessage::Error { id, .. }
+            | OutboundMessage::ChatReceipt { id, .. } => id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    V0,
+    V1,
+}
+
+#[derive(Deserialize)]
+struct InboundEnvelope {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    payload: Value,
+    id: Option<String>,
+}
+
+/// Just the id of an envelope, to answer malformed envelopes with it.
+#[derive(Deserialize)]
+struct EnvelopeId {
+    id: Option<String>,
+}
+
+impl ProtocolVersion {
+    /// Turns an inbound message into the v0 representation the update handlers deserialize.
+    pub fn decode(&self, message: InboundFrame) -> Inbound {
+        match self {
+            ProtocolVersion::V0 => Inbound { id: None, update: Ok(message) },
+            ProtocolVersion::V1 => {
+                let envelope: InboundEnvelope = match message.parse() {
+                    Ok(envelope) => envelope,
+                    Err(e) => return Inbound {
+                        id: message.parse::<EnvelopeId>().ok().and_then(|envelope| envelope.id),
+                        update: Err(UpdateError::invalid_message(format!("Invalid envelope: {}", e))),
+                    },
+                };
+                if envelope.v != 1 {
+                    return Inbound {
+                        id: envelope.id,
+                        update: Err(UpdateError::invalid_message(format!("Unsupported protocol version {}", envelope.v))),
+                    };
+                }
+                let update = match envelope.payload {
+                    Value::Null => Value::String(envelope.kind),
+                    payload => json!({ envelope.kind: payload }),
+                };
+                Inbound { id: envelope.id, update: Ok(message.codec().inbound(&update)) }
+            }
+        }
+    }
+
+    pub fn encode(&self, message: &OutboundMessage, seq: u64) -> Value {
+        match self {
+            ProtocolVersion::V0 => Self::encode_v0(message),
+            ProtocolVersion::V1 => Self::encode_v1(message, seq),
+        }
+    }
+
+    fn encode_v0(message: &OutboundMessage) -> Value {
+        match message {
+            OutboundMessage::Update { update, order_state } => {
+                // Unit variants serialize to a bare string, so give them the same shape as the others
+                let mut update = match update {
+                    Value::String(variant) => json!({ variant: null }),
+                    update => update.clone(),
+                };
+                update["order_state"] = Value::String(order_state.clone());
+                update
+            }
+            OutboundMessage::Processed { .. } => json!("PROCESSED"),
+            OutboundMessage::Transition { state, .. } => json!({ "transition": state }),
+            OutboundMessage::Error { error, .. } => error["message"].clone(),
+            OutboundMessage::ChatReceipt { receipt, .. } => json!({ "ChatReceipt": receipt }),
+            OutboundMessage::OrderComplete => json!("ORDER_COMPLETE"),
+        }
+    }
+
+    fn encode_v1(message: &OutboundMessage, seq: u64) -> Value {
+        let (kind, payload, id) = match message {
+            OutboundMessage::Update { update, order_state } => {
+                let (kind, payload) = Self::split_variant(update);
+                return json!({ "v": 1, "type": kind, "seq": seq, "payload": payload, "state": order_state });
+            }
+            OutboundMessage::Processed { id } => ("Processed".to_string(), Value::Null, id),
+            OutboundMessage::Transition { state, id } => ("Transition".to_string(), json!({ "state": state }), id),
+            OutboundMessage::Error { error, order_state, id } => {
+                let mut error = error.clone();
+                error["state"] = Value::String(order_state.clone());
+                ("Error".to_string(), error, id)
+            }
+            OutboundMessage::ChatReceipt { receipt, id } =>
+                ("ChatReceipt".to_string(), serde_json::to_value(receipt).unwrap(), id),
+            OutboundMessage::OrderComplete => ("OrderComplete".to_string(), Value::Null, &None),
+        };
+        let mut envelope = json!({ "v": 1, "type": kind, "seq": seq, "payload": payload });
+        if let Some(id) = id {
+            envelope["id"] = Value::String(id.clone());
+        }
+        envelope
+    }
+
+    /// Splits an externally tagged enum value into its variant name and content.
+    fn split_variant(update: &Value) -> (String, Value) {
+        match update {
+            Value::String(variant) => (variant.clone(), Value::Null),
+            Value::Object(map) if map.len() == 1 => {
+                let (variant, payload) = map.iter().next().unwrap();
+                (variant.clone(), payload.clone())
+            }
+            other => ("Update".to_string(), other.clone()),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+    use axum::http::HeaderValue;
+    use serde_json::{json, Value};
+    use super::{Inbound, OutboundMessage, Protocol, ProtocolVersion, Sequenced, WireCodec};
+
+    fn protocol(name: &'static str) -> Protocol {
+        Protocol::negotiated(Some(&HeaderValue::from_static(name)))
+    }
+
+    /// The update an inbound message carries, as the update handlers see it.
+    fn update(inbound: Inbound) -> Value {
+        inbound.update.unwrap().parse::<Value>().unwrap()
+    }
+
+    #[test]
+    fn negotiates_v0_unless_a_v1_protocol_is_asked_for() {
+        assert_eq!(Protocol::negotiated(None), Protocol { version: ProtocolVersion::V0, codec: WireCodec::Json });
+        assert_eq!(protocol("foodio.v2").version, ProtocolVersion::V0);
+        assert_eq!(protocol("foodio.v1"), Protocol { version: ProtocolVersion::V1, codec: WireCodec::Json });
+        assert_eq!(protocol("foodio.v1+msgpack").codec, WireCodec::MessagePack);
+        assert_eq!(protocol("foodio.v1+cbor").codec, WireCodec::Cbor);
+    }
+
+    #[test]
+    fn passes_v0_messages_through() {
+        let inbound = Protocol::negotiated(None).decode(Message::Text(r#"{"InTransit":{"lat":1.0,"lon":2.0}}"#.to_string())).unwrap();
+        assert_eq!(inbound.id, None);
+        assert_eq!(update(inbound), json!({ "InTransit": { "lat": 1.0, "lon": 2.0 } }));
+    }
+
+    #[test]
+    fn unwraps_v1_envelopes_in_every_codec() {
+        let envelope = json!({ "v": 1, "type": "InTransit", "payload": { "lat": 1.0, "lon": 2.0 }, "id": "7" });
+        let messages = [
+            ("foodio.v1", Message::Text(envelope.to_string())),
+            ("foodio.v1+msgpack", Message::Binary(rmp_serde::to_vec_named(&envelope).unwrap())),
+            ("foodio.v1+cbor", Message::Binary({
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(&envelope, &mut bytes).unwrap();
+                bytes
+            })),
+            // Binary codecs still take JSON in text frames
+            ("foodio.v1+msgpack", Message::Text(envelope.to_string())),
+        ];
+        for (name, message) in messages {
+            let inbound = protocol(name).decode(message).unwrap();
+            assert_eq!(inbound.id.as_deref(), Some("7"), "{}", name);
+            assert_eq!(update(inbound), json!({ "InTransit": { "lat": 1.0, "lon": 2.0 } }), "{}", name);
+        }
+
+        let unit = protocol("foodio.v1").decode(Message::Text(r#"{"v":1,"type":"Delivered"}"#.to_string())).unwrap();
+        assert_eq!(update(unit), json!("Delivered"));
+    }
+
+    #[test]
+    fn rejects_bad_v1_envelopes_keeping_their_id() {
+        let v1 = protocol("foodio.v1");
+        let wrong_version = v1.decode(Message::Text(r#"{"v":2,"type":"Delivered","id":"1"}"#.to_string())).unwrap();
+        assert_eq!(wrong_version.id.as_deref(), Some("1"));
+        assert!(wrong_version.update.is_err());
+
+        let no_type = v1.decode(Message::Text(r#"{"v":1,"id":"2"}"#.to_string())).unwrap();
+        assert_eq!(no_type.id.as_deref(), Some("2"));
+        assert!(no_type.update.is_err());
+
+        assert!(v1.decode(Message::Binary(vec![1, 2, 3])).unwrap().update.is_err());
+        assert!(v1.decode(Message::Ping(Vec::new())).is_none());
+    }
+
+    #[test]
+    fn encodes_messages_per_version() {
+        let update = OutboundMessage::Update { update: json!("ConfirmArrival"), order_state: "OrderInTransit".to_string() };
+        assert_eq!(ProtocolVersion::V0.encode(&update, 3), json!({ "ConfirmArrival": null, "order_state": "OrderInTransit" }));
+        assert_eq!(ProtocolVersion::V1.encode(&update, 3),
+                   json!({ "v": 1, "type": "ConfirmArrival", "seq": 3, "payload": null, "state": "OrderInTransit" }));
+
+        let processed = OutboundMessage::Processed { id: Some("7".to_string()) };
+        assert_eq!(ProtocolVersion::V0.encode(&processed, 4), json!("PROCESSED"));
+        assert_eq!(ProtocolVersion::V1.encode(&processed, 4), json!({ "v": 1, "type": "Processed", "seq": 4, "payload": null, "id": "7" }));
+
+        let error = OutboundMessage::Error { error: json!({ "code": "INVALID_MESSAGE", "message": "Bad" }), order_state: "OrderCreated".to_string(), id: None };
+        assert_eq!(ProtocolVersion::V0.encode(&error, 5), json!("Bad"));
+        assert_eq!(ProtocolVersion::V1.encode(&error, 5)["payload"], json!({ "code": "INVALID_MESSAGE", "message": "Bad", "state": "OrderCreated" }));
+    }
+
+    #[test]
+    fn sends_binary_codecs_as_binary_frames() {
+        let message = Sequenced { seq: 1, message: OutboundMessage::OrderComplete };
+        let Message::Binary(bytes) = protocol("foodio.v1+msgpack").encode(&message) else {
+            panic!("MessagePack isn't sent as a binary frame");
+        };
+        assert_eq!(rmp_serde::from_slice::<Value>(&bytes).unwrap()["type"], json!("OrderComplete"));
+        assert!(matches!(protocol("foodio.v1").encode(&message), Message::Text(_)));
+    }
+}
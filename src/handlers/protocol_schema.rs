@@ -0,0 +1,334 @@
+//! AsyncAPI description of the WebSocket protocol, generated from the `OrderState` message types.
+//!
+//! Print it with `LocationService asyncapi`.
+
+use std::convert::identity;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use crate::handlers::protocol::{PROTOCOL_V0, PROTOCOL_V1, PROTOCOL_V1_CBOR, PROTOCOL_V1_MSGPACK};
+use crate::handlers::state_machine::StateVisitor;
+use crate::handlers::states::visit_states;
+use crate::models::chat::{ChatReceipt, InboundChatUpdate, OutboundChatUpdate};
+use crate::models::error::ErrorCode;
+use crate::models::reassignment::OutboundReassignmentUpdate;
+use crate::models::updates::OrderState;
+
+const ASYNCAPI_VERSION: &str = "2.6.0";
+
+pub struct ProtocolSchema {
+    generator: SchemaGenerator,
+    messages: Map<String, Value>,
+    courier_inbound: Vec<Value>,
+    courier_outbound: Vec<Value>,
+    customer_inbound: Vec<Value>,
+    customer_outbound: Vec<Value>,
+}
+
+impl ProtocolSchema {
+    pub fn generate() -> Value {
+        let settings = SchemaSettings::draft07().with(|s| {
+            s.definitions_path = "#/components/schemas/".to_string();
+        });
+        let mut schema = Self {
+            generator: SchemaGenerator::new(settings),
+            messages: Map::new(),
+            courier_inbound: Vec::new(),
+            courier_outbound: Vec::new(),
+            customer_inbound: Vec::new(),
+            customer_outbound: Vec::new(),
+        };
+        visit_states(&mut schema);
+        schema.add_server_messages();
+        schema.add_chat_messages();
+        schema.add_reassignment_message();
+        schema.into_document()
+    }
+
+    /// Registers the updates of type `T` accepted or sent in `state`, unless the state has none (`()`).
+    /// `wire` turns the schema of `T` into the one of the message as sent.
+    fn message<T: JsonSchema>(&mut self, state: &str, kind: &str, wire: fn(Value) -> Value) -> Option<Value> {
+        if <()>::schema_name() == T::schema_name() {
+            return None;
+        }
+        let name = format!("{}.{}", state, kind);
+        // The update enums of different states share names, so their schemas are inlined in the
+        // messages, which are named after the state, rather than added to the shared definitions
+        let payload = wire(serde_json::to_value(T::json_schema(&mut self.generator)).unwrap());
+        self.messages.insert(name.clone(), json!({
+            "name": kind,
+            "title": format!("{} in {}", kind, state),
+            "summary": format!("Update valid while the order is in state {}", state),
+            "payload": payload,
+            "x-order-state": state,
+        }));
+        Some(json!({ "$ref": format!("#/components/messages/{}", name) }))
+    }
+
+    /// Describes outbound updates as `encode_v0` sends them: every variant as an object, with
+    /// `null` for unit variants, along with the order state in `order_state`.
+    fn outbound_v0(schema: Value) -> Value {
+        let variants = match schema.get("oneOf") {
+            Some(Value::Array(variants)) => variants.clone(),
+            // Enums of unit variants only are a single string enum
+            _ => vec![schema.clone()],
+        };
+        let variants: Vec<Value> = variants.into_iter()
+            .flat_map(|mut variant| match variant.get("enum") {
+                Some(Value::Array(names)) => names.iter()
+                    .map(|name| {
+                        let mut unit = json!({
+                            "type": "object",
+                            "required": [name, "order_state"],
+                            "properties": { name.as_str().unwrap(): { "type": "null" } },
+                            "additionalProperties": false,
+                        });
+                        if let (Some(description), 1) = (variant.get("description"), names.len()) {
+                            unit["description"] = description.clone();
+                        }
+                        unit
+                    })
+                    .map(Self::with_order_state)
+                    .collect(),
+                _ => {
+                    variant["required"].as_array_mut().unwrap().push(json!("order_state"));
+                    vec![Self::with_order_state(variant)]
+                }
+            })
+            .collect();
+        let mut outbound = json!({ "oneOf": variants });
+        if let Some(description) = schema.get("description") {
+            outbound["description"] = description.clone();
+        }
+        outbound
+    }
+
+    fn with_order_state(mut variant: Value) -> Value {
+        variant["properties"]["order_state"] = json!({ "type": "string" });
+        variant
+    }
+
+    /// Messages the server sends in every state, in their v0 form, with the v1 payload in `x-v1-payload`.
+    fn add_server_messages(&mut self) {
+        let error = json!({
+            "type": "object",
+            "required": ["code", "message", "state"],
+            "properties": {
+                "code": self.generator.subschema_for::<ErrorCode>(),
+                "message": { "type": "string" },
+                "state": { "type": "string" },
+            },
+        });
+        let server_messages = [
+            ("Processed", "Acknowledges an update", json!({ "const": "PROCESSED" }), json!({ "type": "null" })),
+            ("Transition", "The order moved to another state", json!({
+                "type": "object",
+                "required": ["transition"],
+                "properties": { "transition": { "type": "string" } },
+            }), json!({
+                "type": "object",
+                "required": ["state"],
+                "properties": { "state": { "type": "string" } },
+            })),
+            ("Error", "An update was rejected. v0 only gets the message", json!({ "type": "string" }), error),
+            ("OrderComplete", "The session is over", json!({ "const": "ORDER_COMPLETE" }), json!({ "type": "null" })),
+        ];
+        for (name, summary, payload, v1_payload) in server_messages {
+            self.messages.insert(name.to_string(), json!({
+                "name": name,
+                "summary": summary,
+                "payload": payload,
+                "x-v1-payload": v1_payload,
+            }));
+            let message = json!({ "$ref": format!("#/components/messages/{}", name) });
+            self.courier_outbound.push(message.clone());
+            self.customer_outbound.push(message);
+        }
+    }
+
+    /// Chat messages, exchanged in every state but the last.
+    fn add_chat_messages(&mut self) {
+        let inbound = serde_json::to_value(self.generator.subschema_for::<InboundChatUpdate>()).unwrap();
+        let outbound = Self::outbound_v0(serde_json::to_value(OutboundChatUpdate::json_schema(&mut self.generator)).unwrap());
+        let receipt = serde_json::to_value(self.generator.subschema_for::<ChatReceipt>()).unwrap();
+        let messages = [
+            ("ChatMessage", "Chat message to the other participant", inbound),
+            ("RelayedChatMessage", "Chat message from the other participant", outbound),
+        ];
+        for (name, summary, payload) in messages {
+            self.messages.insert(name.to_string(), json!({ "name": name, "summary": summary, "payload": payload }));
+        }
+        self.messages.insert("ChatReceipt".to_string(), json!({
+            "name": "ChatReceipt",
+            "summary": "Answers a chat message",
+            "payload": {
+                "type": "object",
+                "required": ["ChatReceipt"],
+                "properties": { "ChatReceipt": receipt },
+                "additionalProperties": false,
+            },
+            "x-v1-payload": receipt,
+        }));
+        let reference = |name: &str| json!({ "$ref": format!("#/components/messages/{}", name) });
+        self.courier_inbound.push(reference("ChatMessage"));
+        self.customer_inbound.push(reference("ChatMessage"));
+        for name in ["RelayedChatMessage", "ChatReceipt"] {
+            self.courier_outbound.push(reference(name));
+            self.customer_outbound.push(reference(name));
+        }
+    }
+
+    /// Tells the customer the order was handed to another courier.
+    fn add_reassignment_message(&mut self) {
+        let payload = Self::outbound_v0(serde_json::to_value(OutboundReassignmentUpdate::json_schema(&mut self.generator)).unwrap());
+        let name = "CourierReassigned";
+        self.messages.insert(name.to_string(), json!({
+            "name": name,
+            "summary": "Another courier took over the order",
+            "payload": payload,
+        }));
+        self.customer_outbound.push(json!({ "$ref": format!("#/components/messages/{}", name) }));
+    }
+
+    fn into_document(mut self) -> Value {
+        let schemas = serde_json::to_value(self.generator.take_definitions()).unwrap();
+        json!({
+            "asyncapi": ASYNCAPI_VERSION,
+            "info": {
+                "title": "Order tracking WebSocket protocol",
+                "version": env!("CARGO_PKG_VERSION"),
+                "description": format!(
+                    "Message payloads are described in their v0 ({}) form: the serde representation of \
+                     each state's update enums. Outbound updates are always objects, with `null` as the \
+                     value of variants without content, and carry an `order_state` field. \
+                     With {}, {} or {} the variant name is sent in the envelope's `type` and its content \
+                     in `payload`, the order state of updates in `state`. Server messages whose v1 payload \
+                     differs describe it in `x-v1-payload`. Inbound envelopes may carry a string `id`, \
+                     which is echoed in the `Processed`, `Error`, `Transition` or `ChatReceipt` message \
+                     answering them.",
+                    PROTOCOL_V0, PROTOCOL_V1, PROTOCOL_V1_MSGPACK, PROTOCOL_V1_CBOR),
+            },
+            "channels": {
+                "/ws/{order_id}/courier": Self::channel(self.courier_inbound, self.courier_outbound),
+                "/ws/{order_id}/customer": Self::channel(self.customer_inbound, self.customer_outbound),
+            },
+            "components": {
+                "messages": self.messages,
+                "schemas": schemas,
+            },
+        })
+    }
+
+    fn channel(inbound: Vec<Value>, outbound: Vec<Value>) -> Value {
+        json!({
+            "parameters": {
+                "order_id": { "schema": { "type": "string" } },
+            },
+            "publish": { "message": { "oneOf": inbound } },
+            "subscribe": { "message": { "oneOf": outbound } },
+        })
+    }
+}
+
+impl StateVisitor for ProtocolSchema {
+    fn visit<S: OrderState>(&mut self) {
+        let state = S::state_name();
+        if let Some(message) = self.message::<S::InboundCourierUpdate>(state, "InboundCourierUpdate", identity) {
+            self.courier_inbound.push(message);
+        }
+        if let Some(message) = self.message::<S::OutboundCourierUpdate>(state, "OutboundCourierUpdate", Self::outbound_v0) {
+            self.courier_outbound.push(message);
+        }
+        if let Some(message) = self.message::<S::InboundCustomerUpdate>(state, "InboundCustomerUpdate", identity) {
+            self.customer_inbound.push(message);
+        }
+        if let Some(message) = self.message::<S::OutboundCustomerUpdate>(state, "OutboundCustomerUpdate", Self::outbound_v0) {
+            self.customer_outbound.push(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonschema::JSONSchema;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+    use crate::handlers::protocol::{OutboundMessage, ProtocolVersion};
+    use crate::models::chat::{ChatEntry, ChatReceipt, ChatRole, OutboundChatUpdate};
+    use crate::models::error::UpdateError;
+    use crate::models::position::{Distance, Position};
+    use crate::models::reassignment::{CourierAssignment, OutboundReassignmentUpdate};
+    use crate::models::updates::{id_verification, order_created, order_in_transit, substitution_approval};
+    use super::ProtocolSchema;
+
+    fn update<T: Serialize>(update: T) -> OutboundMessage {
+        OutboundMessage::Update { update: serde_json::to_value(update).unwrap(), order_state: "OrderInTransit".to_string() }
+    }
+
+    /// Validates `value` against the schema at `pointer` in the `message` of `document`.
+    fn assert_valid(document: &Value, message: &str, pointer: &str, value: &Value) {
+        let payload = document["components"]["messages"][message].pointer(pointer)
+            .unwrap_or_else(|| panic!("{} has no {}", message, pointer));
+        // References point into the document's components
+        let schema = json!({ "components": document["components"], "allOf": [payload] });
+        let compiled = JSONSchema::compile(&schema).unwrap();
+        let errors: Vec<String> = match compiled.validate(value) {
+            Ok(()) => return,
+            Err(errors) => errors.map(|e| e.to_string()).collect(),
+        };
+        panic!("{} doesn't match {}{}: {:?}", value, message, pointer, errors);
+    }
+
+    #[test]
+    fn encoded_messages_match_the_schema() {
+        let document = ProtocolSchema::generate();
+        let position = Position { lat: 52.5, lon: 13.4 };
+        let distance = Distance { km: 1.5 };
+        let substitution = substitution_approval::Substitution { item: "Milk".to_string(), replacement: "Oat milk".to_string() };
+        let updates = [
+            ("OrderCreated.OutboundCourierUpdate", update(order_created::OutboundCourierUpdate::SuggestTookOrder)),
+            ("OrderInTransit.OutboundCourierUpdate", update(order_in_transit::OutboundCourierUpdate::ConfirmArrival)),
+            ("OrderInTransit.OutboundCourierUpdate", update(order_in_transit::OutboundCourierUpdate::RouteDeviation(distance))),
+            ("OrderInTransit.OutboundCustomerUpdate", update(order_in_transit::OutboundCustomerUpdate::InTransit(position))),
+            ("OrderInTransit.OutboundCustomerUpdate", update(order_in_transit::OutboundCustomerUpdate::OrderNearby(distance))),
+            ("OrderInTransit.OutboundCustomerUpdate", update(order_in_transit::OutboundCustomerUpdate::Delayed)),
+            ("OrderInTransit.OutboundCustomerUpdate", update(order_in_transit::OutboundCustomerUpdate::Resumed)),
+            ("OrderInTransit.OutboundCustomerUpdate", update(order_in_transit::OutboundCustomerUpdate::CourierStatus(
+                order_in_transit::CourierStatus::WaitingAtDoor))),
+            ("SubstitutionApproval.OutboundCourierUpdate", update(substitution_approval::OutboundCourierUpdate::SubstitutionApproved("Milk".to_string()))),
+            ("SubstitutionApproval.OutboundCourierUpdate", update(substitution_approval::OutboundCourierUpdate::SubstitutionRejected("Milk".to_string()))),
+            ("SubstitutionApproval.OutboundCustomerUpdate", update(substitution_approval::OutboundCustomerUpdate::SubstitutionProposed(substitution))),
+            ("IdVerification.OutboundCustomerUpdate", update(id_verification::OutboundCustomerUpdate::IdVerificationFailed)),
+            ("RelayedChatMessage", update(OutboundChatUpdate::ChatMessage(ChatEntry {
+                message_id: 1,
+                from: ChatRole::Courier,
+                text: "On my way".to_string(),
+                timestamp: 1_700_000_000_000,
+            }))),
+            ("CourierReassigned", update(OutboundReassignmentUpdate::CourierReassigned(CourierAssignment {
+                courier_id: "courier-2".to_string(),
+                reason: "Vehicle breakdown".to_string(),
+            }))),
+        ];
+        for (message, sample) in &updates {
+            assert_valid(&document, message, "/payload", &ProtocolVersion::V0.encode(sample, 1));
+        }
+
+        let id = Some("42".to_string());
+        let server_messages = [
+            ("Processed", OutboundMessage::Processed { id: id.clone() }),
+            ("Transition", OutboundMessage::Transition { state: "OrderDelivered".to_string(), id: id.clone() }),
+            ("Error", OutboundMessage::Error {
+                error: serde_json::to_value(UpdateError::not_allowed_in_state("OrderDelivered")).unwrap(),
+                order_state: "OrderDelivered".to_string(),
+                id: id.clone(),
+            }),
+            ("ChatReceipt", OutboundMessage::ChatReceipt { receipt: ChatReceipt { message_id: 1, delivered: true }, id }),
+            ("OrderComplete", OutboundMessage::OrderComplete),
+        ];
+        for (message, sample) in &server_messages {
+            assert_valid(&document, message, "/payload", &ProtocolVersion::V0.encode(sample, 1));
+            assert_valid(&document, message, "/x-v1-payload", &ProtocolVersion::V1.encode(sample, 1)["payload"]);
+        }
+    }
+}
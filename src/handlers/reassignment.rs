@@ -0,0 +1,115 @@
+use std::env;
+use std::fmt;
+use std::time::Duration;
+use rdkafka::{ClientConfig, Message};
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+use crate::handlers::event_actor::AdminCommand;
+use crate::handlers::incoming_order_processor::{HANDLERS, INSTANCE_ID};
+use crate::handlers::states::StateKind;
+use crate::models::reassignment::{CourierAssignment, CourierReassignment};
+
+const DEFAULT_REASON: &str = "The order was reassigned to another courier";
+
+#[derive(Debug)]
+pub enum ReassignError {
+    OrderNotFound,
+    SessionEnded,
+    /// The order session refused the reassignment
+    Refused(String),
+}
+
+impl fmt::Display for ReassignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReassignError::OrderNotFound => f.write_str("Order not found"),
+            ReassignError::SessionEnded => f.write_str("Order session has ended"),
+            ReassignError::Refused(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Hands a live order to another courier. The order session resets the order to where the new
+/// courier starts and tells the customer, then the previous courier is disconnected. Messages the
+/// previous courier's socket sent in the meantime are dropped by the session.
+pub async fn reassign(reassignment: CourierReassignment) -> Result<StateKind, ReassignError> {
+    let order_id = reassignment.order_id;
+    let assignment = CourierAssignment {
+        courier_id: reassignment.courier_id,
+        reason: reassignment.reason.unwrap_or_else(|| DEFAULT_REASON.to_string()),
+    };
+    let admin = HANDLERS.get(&order_id)
+        .map(|session| session.admin())
+        .ok_or(ReassignError::OrderNotFound)?;
+    let (reply, result) = oneshot::channel();
+    admin.send(AdminCommand::Reassign(assignment.clone(), reply)).await
+        .map_err(|_| ReassignError::SessionEnded)?;
+    let state = result.await
+        .map_err(|_| ReassignError::SessionEnded)?
+        .map_err(ReassignError::Refused)?;
+
+    if let Some(mut session) = HANDLERS.get_mut(&order_id) {
+        session.reassign_courier(assignment.courier_id, assignment.reason);
+    }
+    Ok(state)
+}
+
+/// Applies the reassignments dispatch publishes to `order_reassignments`.
+///
+/// Every instance reads all reassignments, as any of them may run the order's session, and
+/// ignores those of orders it doesn't run.
+pub struct ReassignmentProcessor;
+
+impl ReassignmentProcessor {
+    pub async fn run_actor() {
+        let broker = env::var("REDPANDA_BROKER").unwrap_or_else(|_| "localhost:19092".to_string());
+        let group_id = env::var("REASSIGNMENT_GROUP_ID").unwrap_or_else(|_| format!(
+            "geolocation_services_reassignments_{}",
+            INSTANCE_ID.as_str()));
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+
+        info!("Starting reassignment processor");
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", broker.as_str())
+            .set("group.id", group_id.as_str())
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            .set("enable.auto.commit", "true")
+            // Sessions don't survive a restart, so older reassignments have nothing to apply to
+            .set("auto.offset.reset", "latest")
+            .set_log_level(RDKafkaLogLevel::Debug)
+            .create()
+            .unwrap();
+
+        consumer
+            .subscribe(&["order_reassignments"])
+            .expect("Can't subscribe to specified topics");
+
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Error reading reassignment: {}", e);
+                    continue;
+                }
+            };
+            let reassignment = match msg.payload_view::<str>().map(|payload| payload.map(serde_json::from_str::<CourierReassignment>)) {
+                Some(Ok(Ok(reassignment))) => reassignment,
+                _ => {
+                    error!("Invalid reassignment at offset {}", msg.offset());
+                    continue;
+                }
+            };
+            let order_id = reassignment.order_id.clone();
+            match reassign(reassignment).await {
+                Ok(state) => info!("Reassigned order {}, now in state {}", order_id, state),
+                Err(ReassignError::OrderNotFound) => {}
+                Err(e) => error!("Can't reassign order {}: {}", order_id, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use once_cell::sync::Lazy;
+use crate::handlers::protocol::OutboundMessage;
+
+pub struct ReplyCacheConfig {
+    /// Number of recent message ids remembered per participant
+    pub size: usize,
+}
+
+impl ReplyCacheConfig {
+    pub fn init() -> Self {
+        Self {
+            size: env::var("DUPLICATE_MESSAGE_WINDOW")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(100),
+        }
+    }
+}
+
+pub static REPLY_CACHE_CONFIG: Lazy<ReplyCacheConfig> = Lazy::new(ReplyCacheConfig::init);
+
+/// Answers to a participant's most recent messages, by the id the client gave them.
+///
+/// Clients retrying over a flaky connection resend messages they got no answer for; a message
+/// whose id is still in the cache is answered with the recorded reply instead of being processed again.
+pub struct ReplyCache {
+    size: usize,
+    ids: VecDeque<String>,
+    replies: HashMap<String, OutboundMessage>,
+}
+
+impl ReplyCache {
+    pub fn new(config: &ReplyCacheConfig) -> Self {
+        Self { size: config.size, ids: VecDeque::new(), replies: HashMap::new() }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OutboundMessage> {
+        self.replies.get(id)
+    }
+
+    /// Records `reply` as the answer to the message it carries the id of, if any.
+    pub fn record(&mut self, reply: &OutboundMessage) {
+        let Some(id) = reply.id() else { return };
+        if self.size == 0 {
+            return;
+        }
+        if self.replies.insert(id.to_string(), reply.clone()).is_none() {
+            self.ids.push_back(id.to_string());
+        }
+        while self.ids.len() > self.size {
+            if let Some(oldest) = self.ids.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::protocol::OutboundMessage;
+    use super::{ReplyCache, ReplyCacheConfig};
+
+    fn processed(id: &str) -> OutboundMessage {
+        OutboundMessage::Processed { id: Some(id.to_string()) }
+    }
+
+    #[test]
+    fn remembers_the_most_recent_replies() {
+        let mut cache = ReplyCache::new(&ReplyCacheConfig { size: 2 });
+        for id in ["1", "2", "3"] {
+            cache.record(&processed(id));
+        }
+        assert!(cache.get("1").is_none());
+        assert!(matches!(cache.get("2"), Some(OutboundMessage::Processed { id: Some(id) }) if id == "2"));
+        assert!(cache.get("3").is_some());
+    }
+
+    #[test]
+    fn replaces_the_reply_to_an_id_without_evicting_others() {
+        let mut cache = ReplyCache::new(&ReplyCacheConfig { size: 2 });
+        cache.record(&processed("1"));
+        cache.record(&processed("2"));
+        cache.record(&OutboundMessage::Transition { state: "OrderInTransit".to_string(), id: Some("2".to_string()) });
+        assert!(cache.get("1").is_some());
+        assert!(matches!(cache.get("2"), Some(OutboundMessage::Transition { .. })));
+    }
+
+    #[test]
+    fn ignores_replies_without_an_id_and_a_zero_size() {
+        let mut cache = ReplyCache::new(&ReplyCacheConfig { size: 2 });
+        cache.record(&OutboundMessage::OrderComplete);
+        cache.record(&OutboundMessage::Processed { id: None });
+        assert!(cache.ids.is_empty());
+
+        let mut disabled = ReplyCache::new(&ReplyCacheConfig { size: 0 });
+        disabled.record(&processed("1"));
+        assert!(disabled.get("1").is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use std::env;
+use std::time::{Duration, Instant};
+use crate::models::position::{Distance, Position};
+use crate::models::route::Route;
+
+pub static ROUTE_DEVIATION_CONFIG: once_cell::sync::Lazy<RouteDeviationConfig> = once_cell::sync::Lazy::new(RouteDeviationConfig::init);
+
+pub struct RouteDeviationConfig {
+    pub threshold_m: f64,
+    pub sustain: Duration,
+}
+
+impl RouteDeviationConfig {
+    pub fn init() -> Self {
+        Self {
+            threshold_m: env::var("ROUTE_DEVIATION_THRESHOLD_M")
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .unwrap_or(200.0),
+            sustain: Duration::from_secs(env::var("ROUTE_DEVIATION_SUSTAIN_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(60)),
+        }
+    }
+}
+
+/// Reports when the courier has stayed further than the threshold from the planned route
+/// for longer than the sustain window.
+///
+/// A deviation is reported once; the courier has to come back within the threshold
+/// before another one can be reported.
+pub struct RouteDeviationDetector {
+    route: Route,
+    threshold_m: f64,
+    sustain: Duration,
+    off_route_since: Option<Instant>,
+    reported: bool,
+}
+
+impl RouteDeviationDetector {
+    pub fn new(route: Route, config: &RouteDeviationConfig) -> Self {
+        Self {
+            route,
+            threshold_m: config.threshold_m,
+            sustain: config.sustain,
+            off_route_since: None,
+            reported: false,
+        }
+    }
+
+    /// Returns the distance from the route and how long the courier has been off it
+    /// when a sustained deviation is detected.
+    pub fn update(&mut self, pos: &Position, now: Instant) -> Option<(Distance, Duration)> {
+        let distance = self.route.cross_track_distance(pos);
+        if distance.meters() <= self.threshold_m {
+            self.off_route_since = None;
+            self.reported = false;
+            return None;
+        }
+
+        let since = *self.off_route_since.get_or_insert(now);
+        let off_route = now.duration_since(since);
+        if !self.reported && off_route >= self.sustain {
+            self.reported = true;
+            Some((distance, off_route))
+        } else {
+            None
+        }
+    }
+}
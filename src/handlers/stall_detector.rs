@@ -0,0 +1,116 @@
+use std::env;
+use std::time::{Duration, Instant};
+use crate::models::position::Position;
+
+pub static STALL_CONFIG: once_cell::sync::Lazy<StallConfig> = once_cell::sync::Lazy::new(StallConfig::init);
+
+pub struct StallConfig {
+    pub radius_m: f64,
+    pub window: Duration,
+    pub notify_customer: bool,
+}
+
+impl StallConfig {
+    pub fn init() -> Self {
+        Self {
+            radius_m: env::var("STALL_RADIUS_M")
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .unwrap_or(30.0),
+            window: Duration::from_secs(env::var("STALL_WINDOW_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(180)),
+            notify_customer: env::var("STALL_NOTIFY_CUSTOMER")
+                .map(|s| s != "false")
+                .unwrap_or(true),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StallEvent {
+    Stalled(Duration),
+    Resumed,
+}
+
+/// Detects a courier that keeps reporting positions within a small radius.
+///
+/// The first position outside the radius of the current anchor becomes the new anchor,
+/// so a stall is only reported after the courier has stayed put for the whole window.
+pub struct StallDetector {
+    radius_m: f64,
+    window: Duration,
+    anchor: Option<(Instant, Position)>,
+    stalled: bool,
+}
+
+impl StallDetector {
+    pub fn new(config: &StallConfig) -> Self {
+        Self { radius_m: config.radius_m, window: config.window, anchor: None, stalled: false }
+    }
+
+    pub fn update(&mut self, pos: &Position, now: Instant) -> Option<StallEvent> {
+        let since = match self.anchor {
+            Some((since, anchor)) if anchor.distance_to(pos).meters() <= self.radius_m => since,
+            Some(_) => {
+                self.anchor = Some((now, *pos));
+                return if std::mem::take(&mut self.stalled) { Some(StallEvent::Resumed) } else { None };
+            }
+            None => {
+                self.anchor = Some((now, *pos));
+                return None;
+            }
+        };
+
+        let stationary = now.duration_since(since);
+        if !self.stalled && stationary >= self.window {
+            self.stalled = true;
+            Some(StallEvent::Stalled(stationary))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::models::position::Position;
+    use super::{StallConfig, StallDetector, StallEvent};
+
+    const PARKED: Position = Position { lat: 52.5, lon: 13.4 };
+    // About 11 m and 111 m from where the courier parked
+    const NEARBY: Position = Position { lat: 52.5001, lon: 13.4 };
+    const AWAY: Position = Position { lat: 52.501, lon: 13.4 };
+
+    fn detector() -> StallDetector {
+        StallDetector::new(&StallConfig { radius_m: 30.0, window: Duration::from_secs(180), notify_customer: true })
+    }
+
+    #[test]
+    fn reports_a_stall_once_and_the_courier_moving_on() {
+        let mut stall = detector();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(stall.update(&PARKED, at(0)), None);
+        assert_eq!(stall.update(&NEARBY, at(179)), None);
+        assert_eq!(stall.update(&PARKED, at(180)), Some(StallEvent::Stalled(Duration::from_secs(180))));
+        assert_eq!(stall.update(&NEARBY, at(300)), None);
+        assert_eq!(stall.update(&AWAY, at(301)), Some(StallEvent::Resumed));
+        assert_eq!(stall.update(&AWAY, at(302)), None);
+    }
+
+    #[test]
+    fn restarts_the_window_when_the_courier_moves() {
+        let mut stall = detector();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(stall.update(&PARKED, at(0)), None);
+        assert_eq!(stall.update(&AWAY, at(100)), None);
+        assert_eq!(stall.update(&AWAY, at(200)), None);
+        assert_eq!(stall.update(&AWAY, at(280)), Some(StallEvent::Stalled(Duration::from_secs(180))));
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU32;
+use crate::handlers::odometer::Odometer;
+use crate::handlers::workflow::Workflow;
+use crate::models::order_info::OrderInfo;
+use crate::models::updates::OrderState;
+
+/// The order a session tracks, available to states when they are entered.
+#[derive(Clone)]
+pub struct OrderContext {
+    pub order_id: Arc<String>,
+    pub order_info: Arc<OrderInfo>,
+    pub workflow: &'static Workflow,
+    /// Handoff PIN the customer gives the courier, when PINs are used as proof of delivery
+    pub pin: Option<Arc<String>>,
+    /// Wrong PINs submitted for the order, whichever courier submitted them
+    pub pin_attempts: Arc<AtomicU32>,
+    /// Counts the courier's distance across states, so a state's first position is measured
+    /// from the last one of the state before
+    pub odometer: Arc<Mutex<Odometer>>,
+}
+
+/// Creates a state when the order enters it.
+pub trait EnterState: OrderState + Sized {
+    fn enter(order: &OrderContext) -> Self;
+}
+
+/// Called with every state declared in the state machine, see `visit_states`.
+pub trait StateVisitor {
+    fn visit<S: OrderState>(&mut self);
+}
+
+/// Declares the order states, the updates each role exchanges in them and the states they may
+/// move to.
+///
+/// Generates `StateKind`, which parses from and displays as the state name, the `OrderState`
+/// impls, `is_courier_update` / `is_customer_update` and `visit_states`. Every state also needs an `UpdateProcessor` and an `EnterState` impl, and
+/// transition targets must be declared states, or the invocation doesn't compile.
+#[macro_export]
+macro_rules! order_state_machine {
+    (
+        $(
+            $state:ident {
+                courier: $courier_in:ty => $courier_out:ty,
+                customer: $customer_in:ty => $customer_out:ty,
+                transitions: [$($target:ident),* $(,)?] $(,)?
+            }
+        )+
+    ) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[allow(clippy::enum_variant_names)]
+        pub enum StateKind {
+            $($state),+
+        }
+
+        impl StateKind {
+            /// States the order may move to from this one.
+            pub fn transitions(&self) -> &'static [StateKind] {
+                match self {
+                    $(StateKind::$state => &[$(StateKind::$target),*]),+
+                }
+            }
+
+            pub fn can_transition_to(&self, target: StateKind) -> bool {
+                self.transitions().contains(&target)
+            }
+
+            /// Enters the state, returning the handler for the updates received in it.
+            pub fn enter(&self, order: &$crate::handlers::state_machine::OrderContext)
+                         -> Box<dyn $crate::handlers::handler::FrameHandler> {
+                match self {
+                    $(StateKind::$state => Box::new($crate::handlers::handler::WebSocketUpdateHandler::<$state>::new(
+                        <$state as $crate::handlers::state_machine::EnterState>::enter(order)))),+
+                }
+            }
+        }
+
+        impl std::fmt::Display for StateKind {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $(StateKind::$state => f.write_str(stringify!($state))),+
+                }
+            }
+        }
+
+        impl std::str::FromStr for StateKind {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $(stringify!($state) => Ok(StateKind::$state),)+
+                    _ => Err(format!("Unknown state {}", s)),
+                }
+            }
+        }
+
+        $(
+            impl $crate::models::updates::OrderState for $state {
+                type InboundCourierUpdate = $courier_in;
+                type OutboundCourierUpdate = $courier_out;
+                type InboundCustomerUpdate = $customer_in;
+                type OutboundCustomerUpdate = $customer_out;
+
+                fn state_name() -> &'static str {
+                    stringify!($state)
+                }
+            }
+        )+
+
+        /// Whether `message` is a courier update in any state, to tell updates sent in the wrong state from invalid ones.
+        pub fn is_courier_update<M: $crate::handlers::handler::Decode>(message: &M) -> bool {
+            $(message.decode::<$courier_in>().is_ok())||+
+        }
+
+        /// Whether `message` is a customer update in any state, to tell updates sent in the wrong state from invalid ones.
+        pub fn is_customer_update<M: $crate::handlers::handler::Decode>(message: &M) -> bool {
+            $(message.decode::<$customer_in>().is_ok())||+
+        }
+
+        pub fn visit_states<V: $crate::handlers::state_machine::StateVisitor>(visitor: &mut V) {
+            $(visitor.visit::<$state>();)+
+        }
+    };
+}
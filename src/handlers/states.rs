@@ -0,0 +1,107 @@
+//! The order states and how orders move between them.
+//!
+//! Adding a state takes its struct and update enums in `models::updates`, an `UpdateProcessor`
+//! impl, an `EnterState` impl below and an entry in the state machine.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::log::warn;
+use crate::handlers::alert_publisher::ALERT_PUBLISHER;
+use crate::handlers::geofence::{GEOFENCE_CONFIG, GeofenceMode, GeofenceTracker};
+use crate::handlers::location_logger::LOCATION_LOGGER;
+use crate::handlers::route_deviation::{ROUTE_DEVIATION_CONFIG, RouteDeviationDetector};
+use crate::handlers::stall_detector::{STALL_CONFIG, StallDetector};
+use crate::handlers::state_machine::{EnterState, OrderContext};
+use crate::handlers::throttle::{PositionThrottle, ThrottleConfig};
+use crate::models::route::Route;
+use crate::models::updates::{id_verification, order_completed, order_created, order_in_transit, substitution_approval,
+                             IdVerification, OrderCreated, OrderDelivered, OrderInTransit, OrderState, SubstitutionApproval};
+
+crate::order_state_machine! {
+    OrderCreated {
+        courier: order_created::InboundCourierUpdate => order_created::OutboundCourierUpdate,
+        customer: () => (),
+        transitions: [SubstitutionApproval, OrderInTransit],
+    }
+
+    SubstitutionApproval {
+        courier: substitution_approval::InboundCourierUpdate => substitution_approval::OutboundCourierUpdate,
+        customer: substitution_approval::InboundCustomerUpdate => substitution_approval::OutboundCustomerUpdate,
+        transitions: [OrderInTransit],
+    }
+
+    OrderInTransit {
+        courier: order_in_transit::InboundCourierUpdate => order_in_transit::OutboundCourierUpdate,
+        customer: () => order_in_transit::OutboundCustomerUpdate,
+        transitions: [IdVerification, OrderDelivered],
+    }
+
+    IdVerification {
+        courier: id_verification::InboundCourierUpdate => (),
+        customer: () => id_verification::OutboundCustomerUpdate,
+        transitions: [OrderDelivered],
+    }
+
+    OrderDelivered {
+        courier: () => (),
+        customer: order_completed::InboundCustomerUpdate => (),
+        transitions: [],
+    }
+}
+
+impl EnterState for OrderCreated {
+    fn enter(order: &OrderContext) -> Self {
+        let config = &GEOFENCE_CONFIG;
+        OrderCreated {
+            pickup: order.order_info.restaurant
+                .filter(|_| config.pickup_mode != GeofenceMode::Off)
+                .map(|center| GeofenceTracker::new(center, config.pickup_radius_m, Duration::ZERO)),
+            odometer: order.odometer.clone(),
+        }
+    }
+}
+
+impl EnterState for OrderInTransit {
+    fn enter(order: &OrderContext) -> Self {
+        let config = &GEOFENCE_CONFIG;
+        OrderInTransit {
+            order_id: order.order_id.clone(),
+            courier_id: Arc::new(order.order_info.courier_id.clone()),
+            logger: LOCATION_LOGGER.get().unwrap().clone(),
+            alerts: ALERT_PUBLISHER.get().unwrap().clone(),
+            throttle: PositionThrottle::new(ThrottleConfig::for_state(OrderInTransit::state_name())),
+            dropoff: order.order_info.destination
+                .filter(|_| config.dropoff_mode != GeofenceMode::Off)
+                .map(|center| GeofenceTracker::new(center, config.dropoff_radius_m, config.dropoff_dwell)),
+            route_deviation: order.order_info.route.as_deref()
+                .and_then(|polyline| Route::decode(polyline)
+                    .map_err(|e| warn!("Ignoring route of order {}: {}", order.order_id, e))
+                    .ok())
+                .map(|route| RouteDeviationDetector::new(route, &ROUTE_DEVIATION_CONFIG)),
+            stall: StallDetector::new(&STALL_CONFIG),
+            odometer: order.odometer.clone(),
+            pin: order.pin.clone(),
+            pin_attempts: order.pin_attempts.clone(),
+            proof: None,
+        }
+    }
+}
+
+impl EnterState for SubstitutionApproval {
+    fn enter(_order: &OrderContext) -> Self {
+        SubstitutionApproval { pending: HashMap::new() }
+    }
+}
+
+impl EnterState for IdVerification {
+    fn enter(_order: &OrderContext) -> Self {
+        IdVerification {}
+    }
+}
+
+impl EnterState for OrderDelivered {
+    fn enter(_order: &OrderContext) -> Self {
+        OrderDelivered {}
+    }
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::time::{Duration, Instant};
+use crate::models::position::Position;
+
+/// Throttling settings for position updates received in a given order state.
+///
+/// Every value is read from `<STATE>_<SETTING>` (e.g. `ORDER_IN_TRANSIT_NOTIFY_MIN_INTERVAL_MS`),
+/// falling back to the unprefixed `<SETTING>` and then to `0`, which disables the check.
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottleConfig {
+    pub notify_min_interval: Duration,
+    pub notify_min_distance_m: f64,
+    pub log_min_interval: Duration,
+    pub log_min_distance_m: f64,
+}
+
+impl ThrottleConfig {
+    pub fn for_state(state_name: &str) -> Self {
+        let prefix = env_prefix(state_name);
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name))
+            .or_else(|_| env::var(name))
+            .ok();
+        let millis = |name: &str| Duration::from_millis(var(name)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0));
+        let meters = |name: &str| var(name)
+            .and_then(|s| s.parse::<f64>().ok())
+            .unwrap_or(0.0);
+
+        Self {
+            notify_min_interval: millis("NOTIFY_MIN_INTERVAL_MS"),
+            notify_min_distance_m: meters("NOTIFY_MIN_DISTANCE_M"),
+            log_min_interval: millis("LOG_MIN_INTERVAL_MS"),
+            log_min_distance_m: meters("LOG_MIN_DISTANCE_M"),
+        }
+    }
+}
+
+/// Converts a state name such as `OrderInTransit` into an env var prefix (`ORDER_IN_TRANSIT`).
+fn env_prefix(state_name: &str) -> String {
+    let mut prefix = String::with_capacity(state_name.len() + 4);
+    for (i, c) in state_name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            prefix.push('_');
+        }
+        prefix.push(c.to_ascii_uppercase());
+    }
+    prefix
+}
+
+/// Lets a position through only if enough time has passed and the courier has moved far enough
+/// since the last position it let through.
+struct Gate {
+    min_interval: Duration,
+    min_distance_m: f64,
+    last: Option<(Instant, Position)>,
+}
+
+impl Gate {
+    fn new(min_interval: Duration, min_distance_m: f64) -> Self {
+        Self { min_interval, min_distance_m, last: None }
+    }
+
+    fn admit(&mut self, pos: &Position, now: Instant) -> bool {
+        let admitted = match &self.last {
+            None => true,
+            Some((at, last)) => now.duration_since(*at) >= self.min_interval
+                && last.distance_to(pos).meters() >= self.min_distance_m,
+        };
+        if admitted {
+            self.last = Some((now, *pos));
+        }
+        admitted
+    }
+}
+
+/// Decides which incoming courier positions are forwarded to the customer and which are logged.
+///
+/// Both cadences are tracked independently, so logging can keep a denser track than what
+/// the customer sees (or the other way around).
+pub struct PositionThrottle {
+    notify: Gate,
+    log: Gate,
+}
+
+impl PositionThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            notify: Gate::new(config.notify_min_interval, config.notify_min_distance_m),
+            log: Gate::new(config.log_min_interval, config.log_min_distance_m),
+        }
+    }
+
+    pub fn should_notify(&mut self, pos: &Position, now: Instant) -> bool {
+        self.notify.admit(pos, now)
+    }
+
+    pub fn should_log(&mut self, pos: &Position, now: Instant) -> bool {
+        self.log.admit(pos, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::models::position::Position;
+    use super::{env_prefix, PositionThrottle, ThrottleConfig};
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            notify_min_interval: Duration::from_secs(10),
+            notify_min_distance_m: 0.0,
+            log_min_interval: Duration::ZERO,
+            log_min_distance_m: 100.0,
+        }
+    }
+
+    #[test]
+    fn derives_env_prefixes_from_state_names() {
+        assert_eq!(env_prefix("OrderInTransit"), "ORDER_IN_TRANSIT");
+        assert_eq!(env_prefix("IdVerification"), "ID_VERIFICATION");
+    }
+
+    #[test]
+    fn notifies_once_the_interval_passed() {
+        let mut throttle = PositionThrottle::new(config());
+        let (start, pos) = (Instant::now(), Position { lat: 52.5, lon: 13.4 });
+        assert!(throttle.should_notify(&pos, start));
+        assert!(!throttle.should_notify(&pos, start + Duration::from_secs(9)));
+        assert!(throttle.should_notify(&pos, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn logs_once_the_courier_moved_far_enough_independently_of_notifications() {
+        let mut throttle = PositionThrottle::new(config());
+        let now = Instant::now();
+        assert!(throttle.should_log(&Position { lat: 52.5, lon: 13.4 }, now));
+        // About 56 m north, then about 111 m from the first position
+        assert!(!throttle.should_log(&Position { lat: 52.5005, lon: 13.4 }, now));
+        assert!(throttle.should_notify(&Position { lat: 52.5005, lon: 13.4 }, now));
+        assert!(throttle.should_log(&Position { lat: 52.501, lon: 13.4 }, now));
+    }
+}
@@ -0,0 +1,34 @@
+use once_cell::sync::Lazy;
+use crate::handlers::order_store::OrderStore;
+use crate::models::order_info::Participants;
+use crate::models::timeline::{Timeline, TimelineEvent};
+
+static TIMELINES: Lazy<OrderStore<TimelineEvent>> =
+    Lazy::new(|| OrderStore::init("timeline", "TIMELINE_MAX_EVENTS", 10_000, "TIMELINE_STORE_DIR", "timelines")
+        .retaining(|event| event.entry.is_lifecycle()));
+
+/// Keeps the event log of active orders in memory, dropping the oldest events beyond
+/// `TIMELINE_MAX_EVENTS` except lifecycle events such as transitions, and writes it to
+/// `TIMELINE_STORE_DIR` once the order completes.
+pub struct TimelineStore;
+
+impl TimelineStore {
+    pub fn record(order_id: &str, event: TimelineEvent) {
+        TIMELINES.record(order_id, event);
+    }
+
+    pub async fn get(order_id: &str) -> Option<Timeline> {
+        match TIMELINES.entries(order_id) {
+            Some(events) => Some(Timeline { order_id: order_id.to_string(), participants: Participants::default(), events }),
+            None => TIMELINES.load(order_id).await,
+        }
+    }
+
+    /// Moves the timeline of a finished order from memory to disk, returning it.
+    pub async fn persist(order_id: &str, participants: Participants) -> Option<Timeline> {
+        let (order_id, events) = TIMELINES.remove(order_id)?;
+        let timeline = Timeline { order_id, participants, events };
+        TIMELINES.write(&timeline.order_id, &timeline).await;
+        Some(timeline)
+    }
+}
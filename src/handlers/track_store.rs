@@ -0,0 +1,32 @@
+use once_cell::sync::Lazy;
+use crate::handlers::order_store::OrderStore;
+use crate::models::order_info::Participants;
+use crate::models::track::{Track, TrackPoint};
+
+static TRACKS: Lazy<OrderStore<TrackPoint>> =
+    Lazy::new(|| OrderStore::init("track", "TRACK_BUFFER_SIZE", 10_000, "TRACK_STORE_DIR", "tracks"));
+
+/// Keeps the track of active orders in bounded in-memory ring buffers and
+/// writes it to `TRACK_STORE_DIR` once the order completes.
+pub struct TrackStore;
+
+impl TrackStore {
+    pub fn record(order_id: &str, point: TrackPoint) {
+        TRACKS.record(order_id, point);
+    }
+
+    pub async fn get(order_id: &str) -> Option<Track> {
+        match TRACKS.entries(order_id) {
+            Some(points) => Some(Track { order_id: order_id.to_string(), participants: Participants::default(), points }),
+            None => TRACKS.load(order_id).await,
+        }
+    }
+
+    /// Moves the track of a finished order from memory to disk, returning it.
+    pub async fn persist(order_id: &str, participants: Participants) -> Option<Track> {
+        let (order_id, points) = TRACKS.remove(order_id)?;
+        let track = Track { order_id, participants, points };
+        TRACKS.write(&track.order_id, &track).await;
+        Some(track)
+    }
+}
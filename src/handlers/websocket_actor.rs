@@ -1,13 +1,25 @@
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Arc;
-use tokio::sync::{mpsc, SemaphorePermit, watch};
-use axum::extract::ws::{Message, WebSocket};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use futures_util::{SinkExt, StreamExt};
 use tokio::task::JoinHandle;
-use tracing::log::{debug, info};
-use crate::handlers::event_actor::EventActor;
-use crate::handlers::incoming_order_processor::HANDLERS;
+use tracing::log::debug;
+use crate::handlers::chat::ChatQueue;
+use crate::handlers::event_actor::{AdminCommand, EventActor, Outbox, SessionStatus, SocketMessage};
+use crate::handlers::odometer::{ODOMETER_CONFIG, Odometer};
+use crate::handlers::proof_of_delivery::{generate_pin, PROOF_CONFIG};
+use crate::handlers::protocol::{OutboundMessage, Protocol, Sequenced};
+use crate::handlers::state_machine::OrderContext;
+use crate::handlers::workflow::Workflow;
+use crate::models::error::{ErrorCode, UpdateError};
+use crate::models::order_info::{OrderInfo, Participants};
+use crate::models::order_outcome::{unix_millis, OrderOutcome};
 
 struct AutoCancelTask<T>(pub JoinHandle<T>);
 
@@ -17,73 +29,229 @@ impl<T> Drop for AutoCancelTask<T> {
     }
 }
 
+/// A participant's WebSocket connection.
+struct Connection {
+    task: AutoCancelTask<()>,
+    close: oneshot::Sender<String>,
+}
+
+impl Connection {
+    fn is_open(&self) -> bool {
+        !self.task.0.is_finished()
+    }
+
+    /// Closes the socket with `reason`, giving it a moment to send the close frame.
+    fn close(self, reason: String) {
+        let Connection { mut task, close } = self;
+        close.send(reason).ok();
+        tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(1), &mut task.0).await.ok();
+        });
+    }
+}
+
+/// State of a running session, as shown by the admin API.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub order_id: String,
+    pub state: String,
+    pub workflow: &'static str,
+    pub customer: ParticipantInfo,
+    pub courier: ParticipantInfo,
+    /// Milliseconds since the Unix epoch
+    pub last_activity: u64,
+}
+
+#[derive(Serialize)]
+pub struct ParticipantInfo {
+    pub id: String,
+    pub connected: bool,
+}
+
 pub struct OrderSessionHandler {
     order_id: Arc<String>,
+    workflow: &'static str,
     customer_id: String,
     courier_id: String,
-    customer: Option<AutoCancelTask<()>>,
-    courier: Option<AutoCancelTask<()>>,
-    handle: AutoCancelTask<()>,
+    pin: Option<Arc<String>>,
+    customer: Option<Connection>,
+    courier: Option<Connection>,
+    /// Runs the order session until the handler is dropped
+    _actor: AutoCancelTask<()>,
     // update_handler: UpdateHandlerActor,
-    inbound_customer: mpsc::Sender<String>,
-    inbound_courier: mpsc::Sender<String>,
-    outbound_customer: watch::Receiver<String>,
-    outbound_courier: watch::Receiver<String>,
+    inbound_customer: mpsc::Sender<SocketMessage>,
+    inbound_courier: mpsc::Sender<SocketMessage>,
+    outbound_customer: watch::Receiver<Option<Sequenced>>,
+    outbound_courier: watch::Receiver<Option<Sequenced>>,
+    customer_chat: ChatQueue,
+    courier_chat: ChatQueue,
+    customer_seq: Arc<AtomicU64>,
+    courier_seq: Arc<AtomicU64>,
+    delivered: mpsc::UnboundedSender<u64>,
+    admin: mpsc::Sender<AdminCommand>,
+    status: watch::Receiver<SessionStatus>,
 }
 
 impl OrderSessionHandler {
-    pub fn new(order_id: String, customer_id: String, courier_id: String, end: tokio::sync::oneshot::Sender<()>) -> Self {
+    pub fn new(order_info: OrderInfo, end: tokio::sync::oneshot::Sender<OrderOutcome>) -> Self {
         let (inbound_customer, inbound_customer_recv) = mpsc::channel(8);
         let (inbound_courier, inbound_courier_recv) = mpsc::channel(8);
-        let (outbound_customer_send, outbound_customer) = watch::channel(String::new());
-        let (outbound_courier_send, outbound_courier) = watch::channel(String::new());
+        let (outbound_customer_send, outbound_customer) = watch::channel(None);
+        let (outbound_courier_send, outbound_courier) = watch::channel(None);
+        let (admin, admin_recv) = mpsc::channel(8);
+        let (delivered, delivered_recv) = mpsc::unbounded_channel();
+        let workflow = Workflow::get(order_info.workflow.as_deref());
+        let (status_send, status) = watch::channel(SessionStatus { state: workflow.initial(), last_activity: unix_millis() });
 
-        let order_id = Arc::new(order_id);
-        let mut operator = EventActor::new(
-            order_id.clone(),
+        let order_id = Arc::new(order_info.order_id.clone());
+        let customer_id = order_info.customer_id.clone();
+        let courier_id = order_info.courier_id.clone();
+        let pin = PROOF_CONFIG.requirement.uses_pin()
+            .then(|| Arc::new(generate_pin(&PROOF_CONFIG)));
+        let order = OrderContext {
+            order_id: order_id.clone(),
+            order_info: Arc::new(order_info),
+            workflow,
+            pin: pin.clone(),
+            pin_attempts: Arc::new(AtomicU32::new(0)),
+            odometer: Arc::new(Mutex::new(Odometer::new(&ODOMETER_CONFIG))),
+        };
+        let customer_outbox = Outbox::new(outbound_customer_send);
+        let courier_outbox = Outbox::new(outbound_courier_send);
+        let (customer_chat, courier_chat) = (customer_outbox.chat(), courier_outbox.chat());
+        let (customer_seq, courier_seq) = (customer_outbox.seq(), courier_outbox.seq());
+        let operator = EventActor::new(
+            order,
             inbound_customer_recv,
             inbound_courier_recv,
-            outbound_customer_send,
-            outbound_courier_send);
+            customer_outbox,
+            courier_outbox,
+            admin_recv,
+            delivered_recv,
+            status_send);
 
         Self {
             order_id: order_id.clone(),
+            workflow: workflow.id,
             customer_id,
             courier_id,
+            pin,
             customer: None,
             courier: None,
             // update_handler: operator,
-            handle: AutoCancelTask(tokio::spawn(async move {
-                operator.run_actor().await;
-                end.send(()).ok();
+            _actor: AutoCancelTask(tokio::spawn(async move {
+                end.send(operator.run_actor().await).ok();
             })),
             inbound_customer,
             outbound_customer,
             inbound_courier,
             outbound_courier,
+            customer_chat,
+            courier_chat,
+            customer_seq,
+            courier_seq,
+            delivered,
+            admin,
+            status,
         }
     }
 
+    pub fn info(&self) -> SessionInfo {
+        let status = *self.status.borrow();
+        let participant = |id: &str, connection: &Option<Connection>| ParticipantInfo {
+            id: id.to_string(),
+            connected: connection.as_ref().is_some_and(Connection::is_open),
+        };
+        SessionInfo {
+            order_id: self.order_id.to_string(),
+            state: status.state.to_string(),
+            workflow: self.workflow,
+            customer: participant(&self.customer_id, &self.customer),
+            courier: participant(&self.courier_id, &self.courier),
+            last_activity: status.last_activity,
+        }
+    }
 
-    pub fn connect_customer(&mut self, ws: WebSocket) {
-        let inbound_customer = self.inbound_customer.clone();
-        let outbound_customer = self.outbound_customer.clone();
+    /// Channel for operator commands to the order session.
+    pub fn admin(&self) -> mpsc::Sender<AdminCommand> {
+        self.admin.clone()
+    }
 
-        let customer =
-            WebsocketActor::new(ws, inbound_customer, outbound_customer);
-        self.customer = Some(AutoCancelTask(tokio::spawn(customer.run_actor())));
+    /// Closes the customer's socket, returning whether they were connected.
+    pub fn disconnect_customer(&mut self, reason: String) -> bool {
+        self.customer.take().map(|connection| connection.close(reason)).is_some()
     }
 
-    pub fn connect_courier(&mut self, ws: WebSocket) {
-        let inbound_courier = self.inbound_courier.clone();
-        let outbound_courier = self.outbound_courier.clone();
+    /// Closes the courier's socket, returning whether they were connected.
+    pub fn disconnect_courier(&mut self, reason: String) -> bool {
+        self.courier.take().map(|connection| connection.close(reason)).is_some()
+    }
+
+    /// Hands the order to `courier_id`, closing the previous courier's socket with `reason`.
+    pub fn reassign_courier(&mut self, courier_id: String, reason: String) {
+        self.courier_id = courier_id;
+        self.disconnect_courier(reason);
+    }
+
+    pub fn participants(&self) -> Participants {
+        Participants { customer_id: self.customer_id.clone(), courier_id: self.courier_id.clone() }
+    }
+
+    pub fn customer_id(&self) -> &str {
+        &self.customer_id
+    }
+
+    pub fn courier_id(&self) -> &str {
+        &self.courier_id
+    }
+
+    /// Handoff PIN shown to the customer, when PINs are used as proof of delivery.
+    pub fn pin(&self) -> Option<&str> {
+        self.pin.as_deref().map(String::as_str)
+    }
 
-        let courier =
-            WebsocketActor::new(ws, inbound_courier, outbound_courier);
-        self.courier = Some(AutoCancelTask(tokio::spawn(courier.run_actor())));
+    pub fn connect_customer(&mut self, ws: WebSocket, protocol: Protocol) {
+        let inbound_customer = self.inbound_customer.clone();
+        let (close, close_recv) = oneshot::channel();
+        let outbox = SocketOutbox {
+            latest: self.outbound_customer.clone(),
+            chat: self.customer_chat.clone(),
+            delivered: self.delivered.clone(),
+            seq: self.customer_seq.clone(),
+        };
+        let user_id = Arc::new(self.customer_id.clone());
+        let customer = WebsocketActor::new(ws, protocol, user_id, inbound_customer, outbox, self.status.clone(), close_recv);
+        self.customer = Some(Connection { task: AutoCancelTask(tokio::spawn(customer.run_actor())), close });
+    }
+
+    pub fn connect_courier(&mut self, ws: WebSocket, protocol: Protocol) {
+        let inbound_courier = self.inbound_courier.clone();
+        let (close, close_recv) = oneshot::channel();
+        let outbox = SocketOutbox {
+            latest: self.outbound_courier.clone(),
+            chat: self.courier_chat.clone(),
+            delivered: self.delivered.clone(),
+            seq: self.courier_seq.clone(),
+        };
+        let user_id = Arc::new(self.courier_id.clone());
+        let courier = WebsocketActor::new(ws, protocol, user_id, inbound_courier, outbox, self.status.clone(), close_recv);
+        self.courier = Some(Connection { task: AutoCancelTask(tokio::spawn(courier.run_actor())), close });
     }
 }
 
+/// Close code sent when the server disconnects a participant, e.g. through the admin API.
+const CLOSE_CODE_DISCONNECTED: u16 = 4000;
+
+/// What a socket sends to its participant.
+struct SocketOutbox {
+    /// Latest message of the order session, which replaces any the socket didn't send yet
+    latest: watch::Receiver<Option<Sequenced>>,
+    chat: ChatQueue,
+    /// Tells the order session which chat messages the socket sent
+    delivered: mpsc::UnboundedSender<u64>,
+    seq: Arc<AtomicU64>,
+}
+
 struct WebsocketActor {
     send_task: AutoCancelTask<()>,
     recv_task: AutoCancelTask<()>,
@@ -91,26 +259,86 @@ struct WebsocketActor {
 
 impl WebsocketActor {
     pub fn new(socket: WebSocket,
-               inbound: mpsc::Sender<String>,
-               mut outbound: watch::Receiver<String>) -> Self {
+               protocol: Protocol,
+               user_id: Arc<String>,
+               inbound: mpsc::Sender<SocketMessage>,
+               outbox: SocketOutbox,
+               status: watch::Receiver<SessionStatus>,
+               mut close: oneshot::Receiver<String>) -> Self {
         let (mut ws_sender, mut ws_receiver) = socket.split();
+        let SocketOutbox { latest: mut outbound, chat, delivered, seq } = outbox;
+        // Replies the socket sends without involving the order session
+        let (reply, mut replies) = mpsc::channel::<Sequenced>(8);
 
         let inbound_task = tokio::spawn(async move {
+            let mut rate_limit = RateLimiter::new(WEBSOCKET_CONFIG.max_messages_per_sec);
             while let Some(Ok(msg)) = ws_receiver.next().await {
                 debug!("Received message from courier: {:?}", msg);
-                if let Message::Text(text) = msg {
-                    inbound.send(text).await.unwrap();
+                let Some(update) = protocol.decode(msg) else { continue };
+                if !rate_limit.allow(Instant::now()) {
+                    let error = UpdateError::new(ErrorCode::RateLimited,
+                                                 format!("More than {} messages per second", rate_limit.max));
+                    let message = OutboundMessage::Error {
+                        error: serde_json::to_value(error).unwrap(),
+                        order_state: status.borrow().state.to_string(),
+                        id: update.id,
+                    };
+                    let rejected = Sequenced { seq: seq.fetch_add(1, Ordering::Relaxed) + 1, message };
+                    if reply.send(rejected).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                if inbound.send(SocketMessage { user_id: user_id.clone(), inbound: update }).await.is_err() {
+                    debug!("Order session ended, dropping message");
+                    break;
                 }
             }
         });
 
         let outbound_task = tokio::spawn(async move {
-            while outbound.changed().await.is_ok() {
-                let msg = outbound.borrow().clone();
+            let mut latest: Option<Sequenced> = None;
+            let close_frame = loop {
+                // Pending messages are sent in the order the session produced them
+                let queued = chat.front();
+                let (msg, relayed) = match (latest.take(), queued) {
+                    (Some(msg), Some(queued)) if queued.message.seq < msg.seq => {
+                        latest = Some(msg);
+                        (queued.message, Some(queued.relayed))
+                    }
+                    (Some(msg), _) => (msg, None),
+                    (None, Some(queued)) => (queued.message, Some(queued.relayed)),
+                    (None, None) => select! {
+                        changed = outbound.changed() => match changed {
+                            Ok(()) => {
+                                latest = outbound.borrow().clone();
+                                continue;
+                            }
+                            Err(_) => break None,
+                        },
+                        _ = chat.added() => continue,
+                        Some(rejected) = replies.recv() => (rejected, None),
+                        reason = &mut close => break reason.ok().map(|reason| CloseFrame {
+                            code: CLOSE_CODE_DISCONNECTED,
+                            reason: reason.into(),
+                        }),
+                    },
+                };
+                let msg = protocol.encode(&msg);
                 debug!("Sending message to courier: {:?}", msg);
-                ws_sender.send(Message::Text(msg)).await.unwrap();
-            }
-            ws_sender.send(Message::Close(None)).await.unwrap();
+                if ws_sender.send(msg).await.is_err() {
+                    debug!("Socket closed, stopping");
+                    return;
+                }
+                // Queued messages are only taken off the queue once sent
+                if let Some(relayed) = relayed {
+                    chat.sent();
+                    if let Some(message_id) = relayed {
+                        delivered.send(message_id).ok();
+                    }
+                }
+            };
+            ws_sender.send(Message::Close(close_frame)).await.ok();
         });
 
         Self {
@@ -125,4 +353,48 @@ impl WebsocketActor {
             _ = &mut self.recv_task.0 => ()
         }
     }
-}
\ No newline at end of file
+}
+
+pub struct WebSocketConfig {
+    /// Inbound messages accepted per connection and second, 0 (the default) for no limit.
+    /// Messages over the limit are answered with a `RATE_LIMITED` error by the socket itself.
+    pub max_messages_per_sec: u32,
+}
+
+impl WebSocketConfig {
+    pub fn init() -> Self {
+        Self {
+            max_messages_per_sec: env::var("WEBSOCKET_MAX_MESSAGES_PER_SEC")
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .unwrap_or(0),
+        }
+    }
+}
+
+pub static WEBSOCKET_CONFIG: Lazy<WebSocketConfig> = Lazy::new(WebSocketConfig::init);
+
+/// Counts inbound messages in fixed one second windows.
+struct RateLimiter {
+    max: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    fn new(max: u32) -> Self {
+        Self { max, window_start: Instant::now(), count: 0 }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        if self.max == 0 {
+            return true;
+        }
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= self.max
+    }
+}
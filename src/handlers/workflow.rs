@@ -0,0 +1,91 @@
+use tracing::log::warn;
+use crate::handlers::states::StateKind;
+
+/// The states an order goes through, in order, selected by the order's `workflow` id.
+///
+/// States only know the state they are followed by in the default flow. When a state moves
+/// on, the order goes through any states the workflow puts in between first.
+pub struct Workflow {
+    pub id: &'static str,
+    pub states: &'static [StateKind],
+}
+
+pub const DEFAULT_WORKFLOW: &str = "restaurant";
+
+pub static WORKFLOWS: [Workflow; 3] = [
+    Workflow {
+        id: DEFAULT_WORKFLOW,
+        states: &[StateKind::OrderCreated, StateKind::OrderInTransit, StateKind::OrderDelivered],
+    },
+    Workflow {
+        id: "grocery",
+        states: &[StateKind::OrderCreated, StateKind::SubstitutionApproval, StateKind::OrderInTransit, StateKind::OrderDelivered],
+    },
+    Workflow {
+        id: "pharmacy",
+        states: &[StateKind::OrderCreated, StateKind::OrderInTransit, StateKind::IdVerification, StateKind::OrderDelivered],
+    },
+];
+
+impl Workflow {
+    /// Looks up a workflow, falling back to the default one for orders without a known workflow.
+    pub fn get(id: Option<&str>) -> &'static Workflow {
+        let id = id.unwrap_or(DEFAULT_WORKFLOW);
+        WORKFLOWS.iter()
+            .find(|workflow| workflow.id == id)
+            .unwrap_or_else(|| {
+                warn!("Unknown workflow {}, using {}", id, DEFAULT_WORKFLOW);
+                Self::get(None)
+            })
+    }
+
+    pub fn initial(&self) -> StateKind {
+        self.states[0]
+    }
+
+    /// The state to move to from `current` when it asks for `target`.
+    pub fn next(&self, current: StateKind, target: StateKind) -> StateKind {
+        let position = |state| self.states.iter().position(|s| *s == state);
+        match (position(current), position(target)) {
+            (Some(current), Some(target)) if target > current + 1 => self.states[current + 1],
+            _ => target,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::states::StateKind;
+    use super::{Workflow, DEFAULT_WORKFLOW};
+
+    #[test]
+    fn falls_back_to_the_default_workflow() {
+        assert_eq!(Workflow::get(None).id, DEFAULT_WORKFLOW);
+        assert_eq!(Workflow::get(Some("spaceship")).id, DEFAULT_WORKFLOW);
+        assert_eq!(Workflow::get(Some("grocery")).id, "grocery");
+    }
+
+    #[test]
+    fn goes_through_the_states_the_workflow_puts_in_between() {
+        let grocery = Workflow::get(Some("grocery"));
+        assert_eq!(grocery.next(StateKind::OrderCreated, StateKind::OrderInTransit), StateKind::SubstitutionApproval);
+        assert_eq!(grocery.next(StateKind::SubstitutionApproval, StateKind::OrderInTransit), StateKind::OrderInTransit);
+
+        let pharmacy = Workflow::get(Some("pharmacy"));
+        assert_eq!(pharmacy.next(StateKind::OrderInTransit, StateKind::OrderDelivered), StateKind::IdVerification);
+        assert_eq!(pharmacy.next(StateKind::IdVerification, StateKind::OrderDelivered), StateKind::OrderDelivered);
+
+        let restaurant = Workflow::get(None);
+        assert_eq!(restaurant.next(StateKind::OrderCreated, StateKind::OrderInTransit), StateKind::OrderInTransit);
+        assert_eq!(restaurant.next(StateKind::OrderInTransit, StateKind::OrderDelivered), StateKind::OrderDelivered);
+    }
+
+    #[test]
+    fn only_declares_transitions_the_state_machine_allows() {
+        for workflow in super::WORKFLOWS.iter() {
+            for pair in workflow.states.windows(2) {
+                assert!(pair[0].can_transition_to(pair[1]), "{}: {} to {}", workflow.id, pair[0], pair[1]);
+            }
+        }
+    }
+}
@@ -1,12 +1,9 @@
-use std::sync::Arc;
-use axum::extract::State;
 use axum::http::{header, Request, StatusCode};
 use axum::Json;
 use axum::middleware::Next;
 use axum::response::IntoResponse;
 use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde::{Serialize, Deserialize};
-use tracing::error;
 
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
@@ -18,9 +15,14 @@ pub struct ErrorResponse {
 pub struct TokenClaims {
     pub sub: String,
     pub iat: usize,
+    #[serde(default)]
+    pub roles: Vec<String>,
 }
 
-static CONFIG: once_cell::sync::Lazy<JwtConfig> = once_cell::sync::Lazy::new(|| JwtConfig::init());
+/// Role that grants access to the admin API.
+pub const ADMIN_ROLE: &str = "admin";
+
+static CONFIG: once_cell::sync::Lazy<JwtConfig> = once_cell::sync::Lazy::new(JwtConfig::init);
 
 pub async fn auth<B>(
     mut req: Request<B>,
@@ -29,13 +31,7 @@ pub async fn auth<B>(
     let token = req.headers()
         .get(header::AUTHORIZATION)
         .and_then(|auth_header| auth_header.to_str().ok())
-        .and_then(|auth_value| {
-            if auth_value.starts_with("Bearer ") {
-                Some(auth_value[7..].to_owned())
-            } else {
-                None
-            }
-        });
+        .and_then(|auth_value| auth_value.strip_prefix("Bearer ").map(str::to_owned));
 
     let token = token.ok_or_else(|| {
         let json_error = ErrorResponse {
@@ -50,7 +46,7 @@ pub async fn auth<B>(
         &DecodingKey::from_secret(CONFIG.jwt_secret.as_ref()),
         &Validation::default(),
     )
-        .map_err(|e| {
+        .map_err(|_| {
             let json_error = ErrorResponse {
                 status: "fail",
                 message: "Invalid token".to_string(),
@@ -60,11 +56,40 @@ pub async fn auth<B>(
         .claims;
 
     req.extensions_mut().insert(UserId(claims.sub));
+    req.extensions_mut().insert(UserRoles(claims.roles));
+    Ok(next.run(req).await)
+}
+
+/// Lets only users with the admin role through. Must run after `auth`.
+pub async fn require_admin<B>(
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let is_admin = req.extensions()
+        .get::<UserRoles>()
+        .is_some_and(UserRoles::is_admin);
+    if !is_admin {
+        let json_error = ErrorResponse {
+            status: "fail",
+            message: "Admin role required".to_string(),
+        };
+        return Err((StatusCode::FORBIDDEN, Json(json_error)));
+    }
     Ok(next.run(req).await)
 }
 
+#[derive(Clone)]
 pub struct UserId(pub String);
 
+#[derive(Clone)]
+pub struct UserRoles(pub Vec<String>);
+
+impl UserRoles {
+    pub fn is_admin(&self) -> bool {
+        self.0.iter().any(|role| role == ADMIN_ROLE)
+    }
+}
+
 pub struct JwtConfig {
     pub jwt_secret: String,
 }
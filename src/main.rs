@@ -15,14 +15,27 @@
 //! ```not_rust
 //! cargo run -p example-websockets --bin example-client
 //! ```
+//!
+//! Print the AsyncAPI description of the WebSocket protocol with
+//! ```not_rust
+//! cargo run -- asyncapi
+//! ```
+//!
+//! Print the JSON schema of the `processed_orders` event with
+//! ```not_rust
+//! cargo run -- completion-schema
+//! ```
 
 mod models;
 mod handlers;
 mod jwt_auth;
+mod admin;
 
-use axum::{extract::ws::{WebSocketUpgrade}, response::IntoResponse, routing::get, Router, TypedHeader, Server};
+use axum::{extract::ws::{WebSocketUpgrade}, response::IntoResponse, routing::{get, post}, Json, Router, TypedHeader, Server};
+use serde::Deserialize;
+use serde_json::json;
 
-use std::{env, net::SocketAddr, path::PathBuf};
+use std::net::SocketAddr;
 use tower_http::{
     trace::{DefaultMakeSpan, TraceLayer},
 };
@@ -31,19 +44,43 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 //allows to extract the IP of connecting user
 use axum::extract::connect_info::ConnectInfo;
-use axum::extract::Path;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query};
+use axum::Extension;
 use axum::http::StatusCode;
-use dashmap::DashMap;
-use tokio::sync::Semaphore;
 
 //allows to split the websocket stream into separate TX and RX branches
-use tracing::log::info;
-use crate::handlers::incoming_order_processor::{HANDLERS, HOST, IncomingOrderProcessor, PORT, SEMAPHORE};
+use tracing::log::{debug, error, info};
+use crate::handlers::incoming_order_processor::{HANDLERS, HOST, IncomingOrderProcessor, PORT};
+use crate::handlers::alert_publisher::{ALERT_METRICS, AlertPublisher};
+use crate::handlers::courier_summary::{CourierSummaryProcessor, CourierSummaryStore};
 use crate::handlers::location_logger::LocationLogger;
-use crate::handlers::websocket_actor::OrderSessionHandler;
+use crate::handlers::location_spool::SPOOL_METRICS;
+use crate::handlers::proof_of_delivery::{PROOF_CONFIG, ProofStore};
+use crate::handlers::protocol::{Protocol, SUPPORTED_PROTOCOLS};
+use crate::handlers::protocol_schema::ProtocolSchema;
+use crate::handlers::reassignment::ReassignmentProcessor;
+use crate::handlers::timeline_store::TimelineStore;
+use crate::handlers::track_store::TrackStore;
+use crate::jwt_auth::{UserId, UserRoles};
+use crate::models::order_info::Participants;
+use crate::models::order_outcome::{unix_millis, OrderCompletion};
+use crate::models::proof::ProofKind;
 
 #[tokio::main]
 async fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("asyncapi") => {
+            println!("{}", serde_json::to_string_pretty(&ProtocolSchema::generate()).unwrap());
+            return;
+        }
+        Some("completion-schema") => {
+            println!("{}", serde_json::to_string_pretty(&schemars::schema_for!(OrderCompletion)).unwrap());
+            return;
+        }
+        _ => {}
+    }
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -56,18 +93,29 @@ async fn main() {
     let app = Router::new()
         .route("/ws/:order_id/courier", get(courier_ws_handler))
         .route("/ws/:order_id/customer", get(customer_ws_handler))
+        .route("/orders/:order_id/track", get(order_track_handler))
+        .route("/orders/:order_id/timeline", get(order_timeline_handler))
+        .route("/orders/:order_id/proof/:kind", post(proof_upload_handler)
+            .layer(DefaultBodyLimit::max(PROOF_CONFIG.max_upload_bytes)))
+        .route("/orders/:order_id/pin", get(order_pin_handler))
+        .route("/couriers/:courier_id/summary", get(courier_summary_handler))
+        .nest("/admin", admin::router())
         // logging so we can see whats going on
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         )
-        .layer(axum::middleware::from_fn(jwt_auth::auth));
+        .layer(axum::middleware::from_fn(jwt_auth::auth))
+        .route("/metrics", get(metrics_handler));
 
     // run it with hyper
     info!("listening on {}:{}", HOST.as_str(), PORT.as_str());
 
     tokio::spawn(LocationLogger::run_actor());
+    tokio::spawn(AlertPublisher::run_actor());
     tokio::spawn(IncomingOrderProcessor::run_actor());
+    tokio::spawn(ReassignmentProcessor::run_actor());
+    tokio::spawn(CourierSummaryProcessor::run_actor());
 
     Server::bind(&"0.0.0.0:3000".parse().unwrap())
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
@@ -75,39 +123,164 @@ async fn main() {
         .unwrap();
 }
 
+/// Connects the order's current courier. The order may have been handed to another courier
+/// by the time the socket is upgraded, which is checked again then.
 async fn courier_ws_handler(
     ws: WebSocketUpgrade,
     _user_agent: Option<TypedHeader<headers::UserAgent>>,
     order_id: Path<String>,
     ConnectInfo(_addr): ConnectInfo<SocketAddr>,
+    Extension(UserId(user_id)): Extension<UserId>,
 ) -> impl IntoResponse {
-    if !HANDLERS.contains_key(order_id.as_str()) {
-        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    match HANDLERS.get(order_id.as_str()) {
+        None => return (StatusCode::NOT_FOUND, "Order not found").into_response(),
+        Some(session) if session.courier_id() != user_id => return StatusCode::FORBIDDEN.into_response(),
+        Some(_) => {}
     }
-    ws.on_upgrade(move |socket| {
-        HANDLERS
-            .get_mut(order_id.as_str())
-            .unwrap()
-            .connect_courier(socket);
+    ws.protocols(SUPPORTED_PROTOCOLS).on_upgrade(move |socket| {
+        let protocol = Protocol::negotiated(socket.protocol());
+        match HANDLERS.get_mut(order_id.as_str()) {
+            Some(mut session) if session.courier_id() == user_id => session.connect_courier(socket, protocol),
+            _ => debug!("Courier {} is no longer on order {}, dropping the socket", user_id, order_id.as_str()),
+        }
         futures_util::future::ready(())
     })
 }
 
+/// Connects the order's customer.
 async fn customer_ws_handler(
     ws: WebSocketUpgrade,
     _user_agent: Option<TypedHeader<headers::UserAgent>>,
     order_id: Path<String>,
     ConnectInfo(_addr): ConnectInfo<SocketAddr>,
+    Extension(UserId(user_id)): Extension<UserId>,
 ) -> impl IntoResponse {
-    if !HANDLERS.contains_key(order_id.as_str()) {
-        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    match HANDLERS.get(order_id.as_str()) {
+        None => return (StatusCode::NOT_FOUND, "Order not found").into_response(),
+        Some(session) if session.customer_id() != user_id => return StatusCode::FORBIDDEN.into_response(),
+        Some(_) => {}
     }
-    ws.on_upgrade(move |socket| {
-        HANDLERS
-            .get_mut(order_id.as_str())
-            .unwrap()
-            .connect_customer(socket);
+    ws.protocols(SUPPORTED_PROTOCOLS).on_upgrade(move |socket| {
+        let protocol = Protocol::negotiated(socket.protocol());
+        match HANDLERS.get_mut(order_id.as_str()) {
+            Some(mut session) if session.customer_id() == user_id => session.connect_customer(socket, protocol),
+            _ => debug!("Customer {} is no longer on order {}, dropping the socket", user_id, order_id.as_str()),
+        }
         futures_util::future::ready(())
     })
 }
 
+#[derive(Deserialize)]
+struct TrackQuery {
+    format: Option<String>,
+}
+
+async fn order_track_handler(
+    order_id: Path<String>,
+    Query(query): Query<TrackQuery>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Extension(roles): Extension<UserRoles>,
+) -> impl IntoResponse {
+    let Some(track) = TrackStore::get(order_id.as_str()).await else {
+        return (StatusCode::NOT_FOUND, "Track not found").into_response();
+    };
+    if !may_read_order(&order_id, &track.participants, &user_id, &roles) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match query.format.as_deref() {
+        Some("geojson") => Json(track.to_geojson()).into_response(),
+        _ => Json(track).into_response(),
+    }
+}
+
+/// Whether the user is the order's customer or courier, or an admin. The participants of active
+/// orders come from their session, those of completed ones from the data stored with the order.
+fn may_read_order(order_id: &str, stored: &Participants, user_id: &str, roles: &UserRoles) -> bool {
+    roles.is_admin() || match HANDLERS.get(order_id) {
+        Some(session) => session.participants().includes(user_id),
+        None => stored.includes(user_id),
+    }
+}
+
+async fn order_timeline_handler(
+    order_id: Path<String>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Extension(roles): Extension<UserRoles>,
+) -> impl IntoResponse {
+    let Some(timeline) = TimelineStore::get(order_id.as_str()).await else {
+        return (StatusCode::NOT_FOUND, "Timeline not found").into_response();
+    };
+    if !may_read_order(&order_id, &timeline.participants, &user_id, &roles) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    Json(timeline).into_response()
+}
+
+/// Stores a delivery photo or signature uploaded by the order's courier, answering with the
+/// reference the courier submits as proof.
+async fn proof_upload_handler(
+    Path((order_id, kind)): Path<(String, ProofKind)>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    body: Bytes,
+) -> impl IntoResponse {
+    match HANDLERS.get(order_id.as_str()) {
+        None => return (StatusCode::NOT_FOUND, "Order not found").into_response(),
+        Some(session) if session.courier_id() != user_id => return StatusCode::FORBIDDEN.into_response(),
+        Some(_) => {}
+    }
+    if kind == ProofKind::Pin {
+        return (StatusCode::BAD_REQUEST, "PINs are submitted by the courier over the websocket").into_response();
+    }
+    match ProofStore::save(&order_id, kind, &body).await {
+        Ok(reference) => (StatusCode::CREATED, Json(json!({ "reference": reference }))).into_response(),
+        Err(e) => {
+            error!("Failed to store {} of order {}: {}", kind.name(), order_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handoff PIN of an active order, for its customer to give the courier.
+async fn order_pin_handler(
+    order_id: Path<String>,
+    Extension(UserId(user_id)): Extension<UserId>,
+) -> impl IntoResponse {
+    let Some(session) = HANDLERS.get(order_id.as_str()) else {
+        return (StatusCode::NOT_FOUND, "Order not found").into_response();
+    };
+    if session.customer_id() != user_id {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match session.pin() {
+        Some(pin) => Json(json!({ "pin": pin })).into_response(),
+        None => (StatusCode::NOT_FOUND, "Order has no PIN").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct SummaryQuery {
+    /// `YYYY-MM-DD`, today (UTC) if absent
+    date: Option<String>,
+}
+
+/// Orders and distance of a courier on one day, for the courier themselves and admins. Best-effort, see
+/// `CourierSummaryStore`: an order may take a moment to show up, and older orders may be missing.
+async fn courier_summary_handler(
+    courier_id: Path<String>,
+    Query(query): Query<SummaryQuery>,
+    Extension(UserId(user_id)): Extension<UserId>,
+    Extension(roles): Extension<UserRoles>,
+) -> impl IntoResponse {
+    if *courier_id != user_id && !roles.is_admin() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let date = query.date.unwrap_or_else(|| CourierSummaryStore::today(unix_millis()));
+    match CourierSummaryStore::get(&courier_id, &date) {
+        Some(summary) => Json(summary).into_response(),
+        None => (StatusCode::BAD_REQUEST, "Invalid date, expected YYYY-MM-DD").into_response(),
+    }
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    SPOOL_METRICS.render() + &ALERT_METRICS.render()
+}
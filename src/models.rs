@@ -1,4 +1,13 @@
 pub mod position;
 pub mod updates;
 pub mod error;
-pub mod location_log;
\ No newline at end of file
+pub mod location_log;
+pub mod order_info;
+pub mod route;
+pub mod alert;
+pub mod track;
+pub mod proof;
+pub mod chat;
+pub mod timeline;
+pub mod order_outcome;
+pub mod reassignment;
\ No newline at end of file
@@ -0,0 +1,43 @@
+use serde::Serialize;
+use crate::models::position::Position;
+use crate::models::updates::order_in_transit::CourierStatus;
+
+/// Operational alerts published to the alerts topic for dispatch and support.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum Alert {
+    RouteDeviation {
+        order_id: String,
+        courier_id: String,
+        position: Position,
+        distance_m: f64,
+        off_route_secs: u64,
+    },
+    CourierStalled {
+        order_id: String,
+        courier_id: String,
+        position: Position,
+        stationary_secs: u64,
+    },
+    CourierResumed {
+        order_id: String,
+        courier_id: String,
+        position: Position,
+    },
+    CourierStatus {
+        order_id: String,
+        courier_id: String,
+        status: CourierStatus,
+    },
+}
+
+impl Alert {
+    pub fn order_id(&self) -> &str {
+        match self {
+            Alert::RouteDeviation { order_id, .. }
+            | Alert::CourierStalled { order_id, .. }
+            | Alert::CourierResumed { order_id, .. }
+            | Alert::CourierStatus { order_id, .. } => order_id,
+        }
+    }
+}
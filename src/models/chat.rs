@@ -0,0 +1,51 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Chat update both participants can send in every state but the last.
+#[derive(Deserialize, JsonSchema)]
+pub enum InboundChatUpdate {
+    ChatMessage(ChatText),
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChatText {
+    pub text: String,
+}
+
+/// Chat message relayed to the other participant.
+#[derive(Serialize, JsonSchema)]
+pub enum OutboundChatUpdate {
+    ChatMessage(ChatEntry),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    Courier,
+    Customer,
+}
+
+#[derive(Serialize, Clone, Debug, JsonSchema)]
+pub struct ChatEntry {
+    /// Number of the message in the order's chat, starting at 1
+    pub message_id: u64,
+    pub from: ChatRole,
+    pub text: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Tells the sender of a chat message that it was relayed, and again once it was delivered.
+#[derive(Serialize, Clone, Debug, JsonSchema)]
+pub struct ChatReceipt {
+    pub message_id: u64,
+    /// Whether the other participant's socket sent the message
+    pub delivered: bool,
+}
+
+/// Chat of a completed order, published to `order_chat_transcripts`.
+#[derive(Serialize)]
+pub struct ChatTranscript<'a> {
+    pub order_id: &'a str,
+    pub messages: &'a [ChatEntry],
+}
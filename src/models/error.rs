@@ -1,9 +1,7 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-
-pub struct ProcessorError {
-    pub error: String,
-}
+use schemars::JsonSchema;
+use serde::Serialize;
 
 pub struct ErrorWithMessage {
     pub message: String,
@@ -31,4 +29,38 @@ impl Display for ErrorWithMessage {
 
 impl Error for ErrorWithMessage {
 
-}
\ No newline at end of file
+}
+
+/// Error codes sent to participants when an update is rejected.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The message couldn't be decoded as an update.
+    InvalidMessage,
+    /// The update exists, but isn't accepted in the order's current state.
+    NotAllowedInState,
+    /// The participant sent more messages than allowed.
+    RateLimited,
+    /// The order's chat, or the recipient's queue of undelivered chat messages, is full.
+    ChatLimitReached,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UpdateError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl UpdateError {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        Self { code, message }
+    }
+
+    pub fn invalid_message(message: String) -> Self {
+        Self::new(ErrorCode::InvalidMessage, message)
+    }
+
+    pub fn not_allowed_in_state(state: &str) -> Self {
+        Self::new(ErrorCode::NotAllowedInState, format!("Update not allowed in state {}", state))
+    }
+}
@@ -12,3 +12,49 @@ impl LocationLog {
     }
 }
 
+/// Location log entry carrying enough context to be written to a topic shared by all orders.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct LocationLogEnvelope {
+    pub order_id: String,
+    pub courier_id: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl LocationLogEnvelope {
+    pub fn log(&self) -> LocationLog {
+        LocationLog::new(self.lat, self.lon)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct LocationLogPoint {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Consecutive location logs of one order, published as a single record on the shared topic.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct LocationLogBatch {
+    pub order_id: String,
+    pub courier_id: String,
+    pub points: Vec<LocationLogPoint>,
+}
+
+impl LocationLogBatch {
+    pub fn new(envelope: LocationLogEnvelope) -> Self {
+        Self {
+            points: vec![LocationLogPoint { timestamp: envelope.timestamp, lat: envelope.lat, lon: envelope.lon }],
+            order_id: envelope.order_id,
+            courier_id: envelope.courier_id,
+        }
+    }
+
+    pub fn push(&mut self, envelope: LocationLogEnvelope) {
+        self.points.push(LocationLogPoint { timestamp: envelope.timestamp, lat: envelope.lat, lon: envelope.lon });
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+use crate::models::position::Position;
+
+#[derive(Deserialize, Clone)]
+pub struct OrderInfo {
+    pub order_id: String,
+    pub customer_id: String,
+    pub courier_id: String,
+    pub restaurant: Option<Position>,
+    pub destination: Option<Position>,
+    /// Planned route as an encoded polyline.
+    pub route: Option<String>,
+    /// Workflow the order follows, e.g. `grocery` or `pharmacy`. Restaurant orders leave it out.
+    pub workflow: Option<String>,
+}
+
+/// Users an order's data may be shown to, besides admins.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Participants {
+    pub customer_id: String,
+    pub courier_id: String,
+}
+
+impl Participants {
+    pub fn includes(&self, user_id: &str) -> bool {
+        self.customer_id == user_id || self.courier_id == user_id
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::models::chat::ChatEntry;
+use crate::models::order_info::OrderInfo;
+use crate::models::position::Distance;
+use crate::models::proof::ProofOfDelivery;
+use crate::models::updates::{OrderDelivered, OrderInTransit, OrderState};
+
+/// Version of the `processed_orders` event, raised whenever a field is removed or changes meaning.
+pub const ORDER_COMPLETION_VERSION: u32 = 1;
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Why an order session ended.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum OutcomeReason {
+    /// The customer confirmed the delivery.
+    Delivered,
+    /// The participants' channels closed before the order was delivered.
+    SessionClosed,
+    /// An operator ended the session.
+    Terminated,
+    /// The session stopped without reporting an outcome.
+    Aborted,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct StateTransition {
+    pub from: String,
+    pub to: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Part of the delivery a distance was travelled in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Leg {
+    /// From taking the order until picking it up
+    Pickup,
+    /// From picking the order up until delivering it
+    Delivery,
+}
+
+/// Distance the courier travelled per leg, as counted by the odometer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+pub struct LegDistances {
+    pub pickup_m: f64,
+    pub delivery_m: f64,
+}
+
+impl LegDistances {
+    pub fn add(&mut self, leg: Leg, distance: Distance) {
+        match leg {
+            Leg::Pickup => self.pickup_m += distance.meters(),
+            Leg::Delivery => self.delivery_m += distance.meters(),
+        }
+    }
+
+    pub fn total_m(&self) -> f64 {
+        self.pickup_m + self.delivery_m
+    }
+}
+
+/// Distance a courier travelled for an order before it was handed to another courier.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct CourierLegs {
+    pub courier_id: String,
+    pub legs: LegDistances,
+}
+
+/// What the session learned about the order, reported when it ends.
+pub struct OrderOutcome {
+    pub order_id: String,
+    pub customer_id: String,
+    pub courier_id: String,
+    pub reason: OutcomeReason,
+    /// State the order ended in, unknown for aborted sessions
+    pub final_state: Option<String>,
+    pub started_at: u64,
+    pub transitions: Vec<StateTransition>,
+    /// Distance the current courier travelled
+    pub legs: LegDistances,
+    /// Couriers the order was taken from, in order
+    pub previous_couriers: Vec<CourierLegs>,
+    pub proof: Option<ProofOfDelivery>,
+    /// Chat messages exchanged by the participants, in order
+    pub chat: Vec<ChatEntry>,
+}
+
+impl OrderOutcome {
+    pub fn new(order_info: &OrderInfo, started_at: u64) -> Self {
+        Self {
+            order_id: order_info.order_id.clone(),
+            customer_id: order_info.customer_id.clone(),
+            courier_id: order_info.courier_id.clone(),
+            reason: OutcomeReason::Aborted,
+            final_state: None,
+            started_at,
+            transitions: Vec::new(),
+            legs: LegDistances::default(),
+            previous_couriers: Vec::new(),
+            proof: None,
+            chat: Vec::new(),
+        }
+    }
+}
+
+/// Event published to `processed_orders` when an order session ends.
+///
+/// Print its JSON schema with `LocationService completion-schema`.
+#[derive(Serialize, JsonSchema)]
+pub struct OrderCompletion {
+    /// Always `ORDER_COMPLETION_VERSION`
+    pub version: u32,
+    pub order_id: String,
+    pub customer_id: String,
+    pub courier_id: String,
+    /// `Delivered` or `Undelivered`, as in the unversioned event
+    pub status: String,
+    pub reason: OutcomeReason,
+    pub final_state: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub started_at: u64,
+    /// Milliseconds since the Unix epoch
+    pub completed_at: u64,
+    pub transitions: Vec<StateTransition>,
+    /// Distance all couriers of the order travelled, the total of `legs` and `previous_couriers`
+    pub distance_m: f64,
+    /// Distance per leg of `courier_id`, for courier pay
+    pub legs: LegDistances,
+    /// Couriers the order was reassigned away from, in order, with the distance each travelled
+    pub previous_couriers: Vec<CourierLegs>,
+    /// Time from the order going into transit until it was delivered
+    pub delivery_duration_secs: Option<u64>,
+    pub proof: Option<ProofOfDelivery>,
+}
+
+impl OrderCompletion {
+    pub fn new(outcome: &OrderOutcome, completed_at: u64) -> Self {
+        let entered = |state: &str| outcome.transitions.iter()
+            .find(|transition| transition.to == state)
+            .map(|transition| transition.timestamp);
+        let delivery_duration_secs = entered(OrderInTransit::state_name())
+            .zip(entered(OrderDelivered::state_name()))
+            .map(|(in_transit, delivered)| delivered.saturating_sub(in_transit) / 1000);
+
+        Self {
+            version: ORDER_COMPLETION_VERSION,
+            order_id: outcome.order_id.clone(),
+            customer_id: outcome.customer_id.clone(),
+            courier_id: outcome.courier_id.clone(),
+            status: match outcome.reason {
+                OutcomeReason::Delivered => "Delivered".to_string(),
+                _ => "Undelivered".to_string(),
+            },
+            reason: outcome.reason,
+            final_state: outcome.final_state.clone(),
+            started_at: outcome.started_at,
+            completed_at,
+            transitions: outcome.transitions.clone(),
+            distance_m: outcome.legs.total_m()
+                + outcome.previous_couriers.iter().map(|previous| previous.legs.total_m()).sum::<f64>(),
+            legs: outcome.legs,
+            previous_couriers: outcome.previous_couriers.clone(),
+            delivery_duration_secs,
+            proof: outcome.proof.clone(),
+        }
+    }
+}
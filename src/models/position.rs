@@ -1,12 +1,32 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
 pub struct Position {
     pub lat: f64,
     pub lon: f64
 }
 
-#[derive(Serialize, Deserialize)]
+impl Position {
+    /// Great-circle (haversine) distance between two positions.
+    pub fn distance_to(&self, other: &Position) -> Distance {
+        let d_lat = (other.lat - self.lat).to_radians();
+        let d_lon = (other.lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2)
+            + self.lat.to_radians().cos() * other.lat.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+        Distance { km: 2.0 * EARTH_RADIUS_KM * a.sqrt().asin() }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
 pub struct Distance {
     pub km: f64
-}
\ No newline at end of file
+}
+
+impl Distance {
+    pub fn meters(&self) -> f64 {
+        self.km * 1000.0
+    }
+}
@@ -0,0 +1,40 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Evidence the courier submits before the order can be marked delivered.
+#[derive(Deserialize, JsonSchema, Debug)]
+pub enum Proof {
+    /// Reference returned when uploading the photo
+    Photo(String),
+    /// Reference returned when uploading the signature
+    Signature(String),
+    /// PIN the customer gave the courier at handoff
+    Pin(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProofKind {
+    Photo,
+    Signature,
+    Pin,
+}
+
+impl ProofKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProofKind::Photo => "photo",
+            ProofKind::Signature => "signature",
+            ProofKind::Pin => "pin",
+        }
+    }
+}
+
+/// Accepted proof of delivery, as recorded with the processed order.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct ProofOfDelivery {
+    pub kind: ProofKind,
+    /// Object store reference of the uploaded photo or signature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
@@ -0,0 +1,23 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Hands a live order to another courier, read from `order_reassignments` or posted to the admin API.
+#[derive(Deserialize)]
+pub struct CourierReassignment {
+    pub order_id: String,
+    pub courier_id: String,
+    /// Shown to the customer and to the previous courier as the close reason
+    pub reason: Option<String>,
+}
+
+/// Tells the customer another courier took over the order, in any state but the last.
+#[derive(Serialize, JsonSchema)]
+pub enum OutboundReassignmentUpdate {
+    CourierReassigned(CourierAssignment),
+}
+
+#[derive(Serialize, Clone, Debug, JsonSchema)]
+pub struct CourierAssignment {
+    pub courier_id: String,
+    pub reason: String,
+}
@@ -0,0 +1,122 @@
+use crate::models::error::ErrorWithMessage;
+use crate::models::position::{Distance, Position};
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Planned route of a courier, decoded from an encoded polyline (precision 5).
+pub struct Route {
+    points: Vec<Position>,
+}
+
+impl Route {
+    pub fn decode(polyline: &str) -> Result<Self, ErrorWithMessage> {
+        let bytes = polyline.as_bytes();
+        let mut index = 0;
+        let (mut lat, mut lon) = (0i64, 0i64);
+        let mut points = Vec::new();
+
+        while index < bytes.len() {
+            lat += Self::next_value(bytes, &mut index)?;
+            lon += Self::next_value(bytes, &mut index)?;
+            points.push(Position { lat: lat as f64 / 1e5, lon: lon as f64 / 1e5 });
+        }
+
+        if points.is_empty() {
+            return Err(ErrorWithMessage::new("Route polyline is empty".to_string()));
+        }
+        Ok(Self { points })
+    }
+
+    fn next_value(bytes: &[u8], index: &mut usize) -> Result<i64, ErrorWithMessage> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.get(*index)
+                .ok_or_else(|| ErrorWithMessage::new("Route polyline is truncated".to_string()))?;
+            *index += 1;
+            if !(63..=126).contains(&byte) || shift > 60 {
+                return Err(ErrorWithMessage::new(format!("Invalid route polyline at byte {}", *index - 1)));
+            }
+            let chunk = (byte - 63) as i64;
+            result |= (chunk & 0x1f) << shift;
+            shift += 5;
+            if chunk < 0x20 {
+                break;
+            }
+        }
+        Ok(if result & 1 == 1 { !(result >> 1) } else { result >> 1 })
+    }
+
+    /// Shortest distance from `pos` to any segment of the route.
+    ///
+    /// Segments are projected onto a plane tangent at `pos`, which is accurate enough
+    /// for the few hundred meters a deviation threshold is expressed in.
+    pub fn cross_track_distance(&self, pos: &Position) -> Distance {
+        let cos_lat = pos.lat.to_radians().cos();
+        let project = |p: &Position| (
+            (p.lon - pos.lon).to_radians() * cos_lat * EARTH_RADIUS_M,
+            (p.lat - pos.lat).to_radians() * EARTH_RADIUS_M,
+        );
+
+        let meters = if self.points.len() == 1 {
+            let (x, y) = project(&self.points[0]);
+            x.hypot(y)
+        } else {
+            self.points.windows(2)
+                .map(|segment| {
+                    let (ax, ay) = project(&segment[0]);
+                    let (bx, by) = project(&segment[1]);
+                    let (dx, dy) = (bx - ax, by - ay);
+                    let len_sq = dx * dx + dy * dy;
+                    let t = if len_sq == 0.0 { 0.0 } else { (-(ax * dx + ay * dy) / len_sq).clamp(0.0, 1.0) };
+                    (ax + t * dx).hypot(ay + t * dy)
+                })
+                .fold(f64::INFINITY, f64::min)
+        };
+        Distance { km: meters / 1000.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::position::Position;
+    use super::Route;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn decodes_the_reference_polyline() {
+        // Example from Google's encoded polyline algorithm format documentation
+        let route = Route::decode("_p~iF~ps|U_ulLnnqC_mqNvxq`@").unwrap();
+        let expected = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+        assert_eq!(route.points.len(), expected.len());
+        for (point, (lat, lon)) in route.points.iter().zip(expected) {
+            assert_close(point.lat, lat, 1e-9);
+            assert_close(point.lon, lon, 1e-9);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_truncated_and_invalid_polylines() {
+        assert!(Route::decode("").is_err());
+        assert!(Route::decode("_p~iF~ps|").is_err());
+        assert!(Route::decode("_p~iF ps|U").is_err());
+    }
+
+    #[test]
+    fn measures_the_distance_of_a_point_off_the_route() {
+        let route = Route { points: vec![Position { lat: 52.0, lon: 13.0 }, Position { lat: 52.1, lon: 13.0 }] };
+        let beside = Position { lat: 52.05, lon: 13.01 };
+        let expected = beside.distance_to(&Position { lat: 52.05, lon: 13.0 }).meters();
+        assert_close(route.cross_track_distance(&beside).meters(), expected, 1.0);
+
+        // Past the end of the route the distance is to its last point
+        let beyond = Position { lat: 52.11, lon: 13.0 };
+        let expected = beyond.distance_to(&route.points[1]).meters();
+        assert_close(route.cross_track_distance(&beyond).meters(), expected, 1.0);
+
+        assert_close(route.cross_track_distance(&Position { lat: 52.05, lon: 13.0 }).meters(), 0.0, 0.01);
+    }
+}
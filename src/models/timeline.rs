@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+use crate::models::chat::ChatRole;
+use crate::models::order_info::Participants;
+use crate::models::proof::ProofOfDelivery;
+
+/// Who caused a timeline event.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Actor {
+    Courier,
+    Customer,
+    System,
+    /// An operator through the admin API, or dispatch through `order_reassignments`
+    Admin,
+}
+
+impl From<ChatRole> for Actor {
+    fn from(role: ChatRole) -> Self {
+        match role {
+            ChatRole::Courier => Actor::Courier,
+            ChatRole::Customer => Actor::Customer,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimelineEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub actor: Actor,
+    /// State the order was in when the event happened
+    pub state: String,
+    #[serde(flatten)]
+    pub entry: TimelineEntry,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum TimelineEntry {
+    SessionStarted,
+    /// An update of the actor was accepted
+    UpdateProcessed { update: Option<Value> },
+    /// An update of the actor was rejected
+    UpdateRejected { error: Value },
+    /// A participant was sent an update
+    Notified { recipient: Actor, update: Value },
+    Transition { from: String, to: String },
+    ProofRecorded { proof: ProofOfDelivery },
+    ChatRelayed { message_id: u64 },
+    OrderComplete,
+    /// The session was ended before the order completed
+    SessionTerminated,
+    /// Another courier took over the order
+    CourierReassigned { from: String, to: String, reason: String },
+}
+
+impl TimelineEntry {
+    /// Whether the event marks a step of the order's lifecycle rather than an update within a step.
+    pub fn is_lifecycle(&self) -> bool {
+        matches!(self, TimelineEntry::SessionStarted
+            | TimelineEntry::Transition { .. }
+            | TimelineEntry::ProofRecorded { .. }
+            | TimelineEntry::OrderComplete
+            | TimelineEntry::SessionTerminated
+            | TimelineEntry::CourierReassigned { .. })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Timeline {
+    pub order_id: String,
+    /// Recorded once the order completes, the session knows them until then
+    #[serde(default)]
+    pub participants: Participants,
+    pub events: Vec<TimelineEvent>,
+}
+
+impl Timeline {
+    /// Splits the timeline into parts whose events take up at most `max_bytes` as JSON. An event
+    /// that is larger on its own is left out.
+    pub fn parts(&self, max_bytes: usize) -> Vec<TimelinePart<'_>> {
+        let mut ranges = Vec::new();
+        let (mut start, mut bytes) = (0, 0);
+        for (i, event) in self.events.iter().enumerate() {
+            // Counting the separating comma
+            let size = serde_json::to_vec(event).map_or(usize::MAX, |json| json.len() + 1);
+            if size > max_bytes {
+                error!("Leaving out {} byte event of the timeline of order {}", size, self.order_id);
+                if start < i {
+                    ranges.push(start..i);
+                }
+                (start, bytes) = (i + 1, 0);
+                continue;
+            }
+            if bytes + size > max_bytes {
+                ranges.push(start..i);
+                (start, bytes) = (i, 0);
+            }
+            bytes += size;
+        }
+        if start < self.events.len() || ranges.is_empty() {
+            ranges.push(start..self.events.len());
+        }
+
+        let parts = ranges.len();
+        ranges.into_iter()
+            .enumerate()
+            .map(|(i, range)| TimelinePart {
+                order_id: &self.order_id,
+                participants: &self.participants,
+                part: i + 1,
+                parts,
+                events: &self.events[range],
+            })
+            .collect()
+    }
+}
+
+/// Part of a timeline as published to `order_timelines`, which takes the timeline of a busy order
+/// in several messages to stay under the broker's message size limit.
+#[derive(Serialize)]
+pub struct TimelinePart<'a> {
+    pub order_id: &'a str,
+    pub participants: &'a Participants,
+    /// Number of this part, from 1 to `parts`
+    pub part: usize,
+    pub parts: usize,
+    pub events: &'a [TimelineEvent],
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::order_info::Participants;
+    use super::{Actor, Timeline, TimelineEntry, TimelineEvent};
+
+    fn event(message_id: u64) -> TimelineEvent {
+        TimelineEvent { timestamp: 0, actor: Actor::Courier, state: "OrderInTransit".to_string(), entry: TimelineEntry::ChatRelayed { message_id } }
+    }
+
+    #[test]
+    fn splits_a_timeline_into_parts_under_the_size_limit() {
+        let timeline = Timeline { order_id: "order".to_string(), participants: Participants::default(), events: (0..10).map(event).collect() };
+        let size = serde_json::to_vec(&timeline.events[0]).unwrap().len() + 1;
+
+        let parts = timeline.parts(3 * size);
+        assert_eq!(parts.iter().map(|part| part.events.len()).collect::<Vec<_>>(), [3, 3, 3, 1]);
+        assert!(parts.iter().enumerate().all(|(i, part)| part.part == i + 1 && part.parts == 4));
+
+        assert_eq!(timeline.parts(usize::MAX).len(), 1);
+        // Events too large for any part are left out
+        let parts = timeline.parts(size - 1);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].events.is_empty());
+    }
+}
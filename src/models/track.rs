@@ -0,0 +1,48 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::models::order_info::Participants;
+use crate::models::position::Position;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl TrackPoint {
+    pub fn now(pos: &Position) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Self { lat: pos.lat, lon: pos.lon, timestamp }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Track {
+    pub order_id: String,
+    /// Recorded once the order completes, the session knows them until then
+    #[serde(default)]
+    pub participants: Participants,
+    pub points: Vec<TrackPoint>,
+}
+
+impl Track {
+    /// Renders the track as a GeoJSON `Feature` with a `LineString` geometry.
+    pub fn to_geojson(&self) -> serde_json::Value {
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": self.points.iter().map(|p| [p.lon, p.lat]).collect::<Vec<_>>(),
+            },
+            "properties": {
+                "order_id": self.order_id,
+                "timestamps": self.points.iter().map(|p| p.timestamp).collect::<Vec<_>>(),
+            }
+        })
+    }
+}
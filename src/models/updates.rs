@@ -1,103 +1,175 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU32;
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
-use crate::models::location_log::LocationLog;
+use schemars::JsonSchema;
+use crate::handlers::geofence::GeofenceTracker;
+use crate::handlers::odometer::Odometer;
+use crate::handlers::route_deviation::RouteDeviationDetector;
+use crate::handlers::stall_detector::StallDetector;
+use crate::handlers::throttle::PositionThrottle;
+use crate::models::alert::Alert;
+use crate::models::location_log::LocationLogEnvelope;
+use crate::models::proof::ProofOfDelivery;
 
 // Order States
 
-pub struct OrderCreated {}
+pub struct OrderCreated {
+    pub pickup: Option<GeofenceTracker>,
+    pub odometer: Arc<Mutex<Odometer>>,
+}
 
 pub struct OrderInTransit {
     pub order_id: Arc<String>,
-    pub logger: tokio::sync::mpsc::Sender<(Arc<String>, LocationLog)>
+    pub courier_id: Arc<String>,
+    pub logger: tokio::sync::mpsc::Sender<LocationLogEnvelope>,
+    pub alerts: tokio::sync::mpsc::Sender<Alert>,
+    pub throttle: PositionThrottle,
+    pub dropoff: Option<GeofenceTracker>,
+    pub route_deviation: Option<RouteDeviationDetector>,
+    pub stall: StallDetector,
+    pub odometer: Arc<Mutex<Odometer>>,
+    /// Handoff PIN the customer gives the courier, when PINs are used as proof
+    pub pin: Option<Arc<String>>,
+    pub pin_attempts: Arc<AtomicU32>,
+    pub proof: Option<ProofOfDelivery>,
 }
 
 pub struct OrderDelivered {}
 
-pub trait OrderState {
-    type InboundCourierUpdate: DeserializeOwned;
-    type OutboundCourierUpdate: Serialize + Send;
-    type InboundCustomerUpdate: DeserializeOwned;
-    type OutboundCustomerUpdate: Serialize + Send;
-
-    fn state_name() -> &'static str;
-}
-
-impl OrderState for OrderCreated {
-    type InboundCourierUpdate = order_created::InboundCourierUpdate;
-    type OutboundCourierUpdate = ();
-    type InboundCustomerUpdate = ();
-    type OutboundCustomerUpdate = order_created::OutboundCustomerUpdate;
-
-    fn state_name() -> &'static str {
-        "OrderCreated"
-    }
+/// Courier is at the store, waiting for the customer to approve substitutions for unavailable items.
+pub struct SubstitutionApproval {
+    /// Proposed substitutions awaiting the customer's decision, by item
+    pub pending: HashMap<String, substitution_approval::Substitution>,
 }
 
-impl OrderState for OrderInTransit {
-    type InboundCourierUpdate = order_in_transit::InboundCourierUpdate;
-    type OutboundCourierUpdate = ();
-    type InboundCustomerUpdate = ();
-    type OutboundCustomerUpdate = order_in_transit::OutboundCustomerUpdate;
-
-    fn state_name() -> &'static str {
-        "OrderInTransit"
-    }
-}
+/// Courier is at the destination, checking the customer's ID before handing the order over.
+pub struct IdVerification {}
 
-impl OrderState for OrderDelivered {
-    type InboundCourierUpdate = ();
-    type OutboundCourierUpdate = order_completed::OutboundCourierUpdate;
-    type InboundCustomerUpdate = order_completed::InboundCustomerUpdate;
-    type OutboundCustomerUpdate = ();
+pub trait OrderState {
+    type InboundCourierUpdate: DeserializeOwned + JsonSchema;
+    type OutboundCourierUpdate: Serialize + Send + JsonSchema;
+    type InboundCustomerUpdate: DeserializeOwned + JsonSchema;
+    type OutboundCustomerUpdate: Serialize + Send + JsonSchema;
 
-    fn state_name() -> &'static str {
-        "OrderDelivered"
-    }
+    fn state_name() -> &'static str;
 }
 
 pub mod order_created {
+    use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
+    use crate::models::position::Position;
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, JsonSchema)]
     pub enum InboundCourierUpdate {
-        TookOrder
+        TookOrder,
+        InTransit(Position)
     }
 
-    #[derive(Serialize)]
-    pub enum OutboundCustomerUpdate {
-        TookOrder
+    #[derive(Serialize, JsonSchema)]
+    pub enum OutboundCourierUpdate {
+        SuggestTookOrder
     }
 }
 
 pub mod order_in_transit {
+    use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
     use crate::models::position::{Distance, Position};
+    use crate::models::proof::Proof;
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, JsonSchema)]
     pub enum InboundCourierUpdate {
         InTransit(Position),
+        SubmitProof(Proof),
+        CourierStatus(CourierStatus),
         Delivered
     }
 
-    #[derive(Serialize)]
+    /// Predefined statuses the courier can send with one tap.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
+    pub enum CourierStatus {
+        StuckInTraffic,
+        CantFindAddress,
+        WaitingAtDoor,
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    pub enum OutboundCourierUpdate {
+        ConfirmArrival,
+        RouteDeviation(Distance)
+    }
+
+    #[derive(Serialize, JsonSchema)]
     pub enum OutboundCustomerUpdate {
         InTransit(Position),
         OrderNearby(Distance),
-        Delivered
+        Delayed,
+        Resumed,
+        CourierStatus(CourierStatus)
     }
 }
 
 pub mod order_completed {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, JsonSchema)]
+    pub enum InboundCustomerUpdate {
+        DeliveryConfirmed
+    }
+}
+
+pub mod substitution_approval {
+    use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
+    use crate::models::position::Position;
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone)]
+    pub struct Substitution {
+        pub item: String,
+        pub replacement: String,
+    }
 
-    #[derive(Serialize)]
+    #[derive(Deserialize, JsonSchema)]
+    pub enum InboundCourierUpdate {
+        /// Accepted so the courier app can keep reporting positions, but not tracked at the store
+        InTransit(#[allow(dead_code)] Position),
+        ProposeSubstitution(Substitution),
+        PickedUp
+    }
+
+    #[derive(Serialize, JsonSchema)]
     pub enum OutboundCourierUpdate {
-        DeliveryConfirmed
+        SubstitutionApproved(String),
+        SubstitutionRejected(String)
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, JsonSchema)]
     pub enum InboundCustomerUpdate {
-        DeliveryConfirmed
+        ApproveSubstitution(String),
+        RejectSubstitution(String)
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    pub enum OutboundCustomerUpdate {
+        SubstitutionProposed(Substitution)
+    }
+}
+
+pub mod id_verification {
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, JsonSchema)]
+    pub enum InboundCourierUpdate {
+        IdVerified,
+        IdRejected
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    pub enum OutboundCustomerUpdate {
+        IdVerificationFailed
     }
 }